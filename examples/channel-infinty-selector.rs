@@ -39,10 +39,9 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
 
     log::debug!("Starting laser-setup controller with args: {:?}", args);
 
-    let mut interface = laser_setup_interface::LaserSetup::new(
-        &args.port,
-        std::time::Duration::from_millis(args.timeout),
-    );
+    let mut interface = laser_setup_interface::LaserSetup::builder(&args.port)
+        .timeout(std::time::Duration::from_millis(args.timeout))
+        .open()?;
 
     let current_state = interface.read().await?;
     log::info!("Current laser-setup state: {:?}", current_state);
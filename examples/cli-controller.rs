@@ -60,10 +60,9 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
 
     log::debug!("Starting laser-setup controller with args: {:?}", args);
 
-    let mut interface = laser_setup_interface::LaserSetup::new(
-        &args.port,
-        std::time::Duration::from_millis(args.timeout),
-    );
+    let mut interface = laser_setup_interface::LaserSetup::builder(&args.port)
+        .timeout(std::time::Duration::from_millis(args.timeout))
+        .open()?;
 
     if args.open && args.close {
         panic!("Open and close flags are mutually exclusive");
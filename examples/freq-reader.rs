@@ -1,6 +1,6 @@
 use clap::Parser;
 
-use laser_setup_interface::{self, I2c};
+use laser_setup_interface::{self, I2cRegisters, RegisterDevice};
 
 /// Laser setup freq reader
 #[derive(Parser, Debug)]
@@ -41,10 +41,9 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
 
     let args = Cli::parse();
 
-    let mut interface = laser_setup_interface::LaserSetup::new(
-        &args.port,
-        std::time::Duration::from_millis(args.timeout),
-    );
+    let mut interface = laser_setup_interface::LaserSetup::builder(&args.port)
+        .timeout(std::time::Duration::from_millis(args.timeout))
+        .open()?;
 
     if args.list {
         println!("Enumerating i2c buses on device port {}", args.port);
@@ -56,35 +55,19 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
         return Ok(());
     }
 
-    interface.select_i2c_bus(args.bus);
+    interface.select_i2c_bus(args.bus).await?;
     println!(
         "Reading frequency port {}, bus: {}, device: 0x{:02x}",
         args.port, args.bus, args.device_addr
     );
 
+    let freq_meter = RegisterDevice::new(args.device_addr);
     loop {
-        let res: Result<[u8; 4], laser_setup_interface::Error> = {
-            let mut buf = [0; std::mem::size_of::<f32>()];
-            let addr = [args.device_reg; 1];
-            let mut ops = vec![
-                laser_setup_interface::Operation::Write(&addr),
-                laser_setup_interface::Operation::Read(&mut buf),
-            ];
-            interface
-                .transaction(args.device_addr, &mut ops)
-                .await
-                .map(|_| buf)
-        };
-
-        match res {
-            Ok(buf) => {
-                let f = {
-                    let byte_array: [u8; 4] = buf[0..4].try_into().unwrap();
-                    f32::from_le_bytes(byte_array)
-                };
-
-                println!("Frequency: {:.2} Hz", f);
-            }
+        match interface
+            .read_reg_f32(&freq_meter, args.device_reg.into())
+            .await
+        {
+            Ok(f) => println!("Frequency: {:.2} Hz", f),
             Err(e) => log::error!("Freqmeter error: {:?}", e),
         }
 
@@ -1,6 +1,6 @@
 use clap::Parser;
 
-use laser_setup_interface::{self, I2c};
+use laser_setup_interface::{ProbeMethod, ScanOptions};
 
 /// Laser setup CLI controller
 #[derive(Parser, Debug)]
@@ -14,10 +14,22 @@ struct Cli {
     #[clap(short, long, default_value = "100")]
     timeout: u64,
 
-    /// Select channel
+    /// Bus to scan, all buses if not set
     #[clap(short('B'), long)]
     bus: Option<u32>,
 
+    /// Probe with one-byte read
+    #[clap(short, long, default_value = "false")]
+    read: bool,
+
+    /// Probe with zero-length write
+    #[clap(short, long, default_value = "false", conflicts_with = "read")]
+    quick: bool,
+
+    /// Probe reserved addresses too
+    #[clap(short, long, default_value = "false")]
+    all: bool,
+
     /// List avalable i2c buses
     #[clap(short('L'), long, default_value = "false")]
     list: bool,
@@ -29,10 +41,9 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
 
     let args = Cli::parse();
 
-    let mut interface = laser_setup_interface::LaserSetup::new(
-        &args.port,
-        std::time::Duration::from_millis(args.timeout),
-    );
+    let mut interface = laser_setup_interface::LaserSetup::builder(&args.port)
+        .timeout(std::time::Duration::from_millis(args.timeout))
+        .open()?;
 
     if args.list {
         println!("Enumerating i2c buses on device port {}", args.port);
@@ -44,36 +55,41 @@ async fn main() -> Result<(), laser_setup_interface::Error> {
         return Ok(());
     }
 
-    if let Some(bus) = args.bus {
-        interface.select_i2c_bus(bus);
+    let options = ScanOptions {
+        method: if args.read {
+            ProbeMethod::ReadByte
+        } else if args.quick {
+            ProbeMethod::QuickWrite
+        } else {
+            ProbeMethod::Auto
+        },
+        skip_reserved: !args.all,
+        ..Default::default()
+    };
+
+    let scans = if let Some(bus) = args.bus {
         println!(
             "Detecting i2c devices on device port {}, bus: {}",
-            args.port,
-            bus
+            args.port, bus
+        );
+        vec![interface.scan_bus(bus, &options).await?]
+    } else {
+        println!(
+            "Detecting i2c devices on device port {}, all buses",
+            args.port
         );
+        let (handle, _) = interface.spawn();
+        handle.scan_all_buses(&options).await?
+    };
 
-        let mut total_found = 0;
-        for addr in 0..=(u8::MAX >> 1) {
-            let mut buf = [0; 1];
-
-            let mut ops = [laser_setup_interface::Operation::Read(&mut buf); 1];
-
-            match interface.transaction(addr, &mut ops).await {
-                Ok(_) => {
-                    println!("Found i2c device at address: 0x{:02x}", addr);
-                    total_found += 1;
-                }
-                Err(laser_setup_interface::Error::I2C(
-                    embedded_hal_async::i2c::ErrorKind::NoAcknowledge(_),
-                )) => { /* Nak: no device present */}
-                Err(e) => panic!("Error: {:?}", e),
-            }
+    for scan in scans {
+        println!("I2c bus {}:", scan.bus);
+        print!("{}", scan);
+        for (addr, e) in scan.errors() {
+            log::error!("Probing 0x{:02x} failed: {}", addr, e.root());
         }
-        log::info!("Found {} i2c devices", total_found);
-
-        Ok(())
-    } else {
-        log::error!("No i2c bus selected!");
-        Err(laser_setup_interface::Error::UnexpectedEndOfStream)
+        log::info!("Found {} i2c devices", scan.found().count());
     }
+
+    Ok(())
 }
@@ -0,0 +1,620 @@
+//! Offline protocol analyzer
+//!
+//! Splits raw dumps of the serial line into messages using `Info::Magick` framing, pairs
+//! requests with responses by id and reports what went wrong:
+//!
+//! ```no_run
+//! # fn test() -> std::io::Result<()> {
+//! use laser_setup_interface::analyzer::Analysis;
+//! use laser_setup_interface::DEFAULT_MAX_FRAME_LENGTH;
+//!
+//! // host -> device and device -> host directions dumped separately, one may be empty
+//! let tx = std::fs::read("tx.bin")?;
+//! let rx = std::fs::read("rx.bin")?;
+//! print!("{}", Analysis::from_raw(&tx, &rx, DEFAULT_MAX_FRAME_LENGTH));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use prost::Message;
+
+use crate::capture::{Capture, Direction};
+use crate::protobuf::messages::{
+    i2c_operation, i2c_request, i2c_response, i2c_result, ActuatorState, I2cResultCode, Info,
+    Request, Response, Status, ValveState,
+};
+use crate::protobuf::protobuf_md_codec::{parse_header, FrameHeader};
+
+/// Decoded message and where it was found
+#[derive(Debug, Clone)]
+pub struct Frame<M> {
+    /// Byte offset in the dump, record index for captures
+    pub offset: usize,
+    /// Known for captures only
+    pub timestamp: Option<Duration>,
+    pub message: M,
+}
+
+/// Why bytes were not decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Bytes before `Info::Magick`
+    Garbage,
+    /// Length prefix is not a valid varint or exceeds the frame limit
+    InvalidLength,
+    /// Frame body is not a valid message
+    Undecodable,
+    /// Dump ends in the middle of a frame
+    Truncated,
+}
+
+/// Range of bytes not belonging to any decoded message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub direction: Direction,
+    pub offset: usize,
+    pub len: usize,
+    pub reason: SkipReason,
+}
+
+/// Request and responses carrying its id
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub request: Frame<Request>,
+    /// Empty if the device did not answer, more than one if the answer was repeated
+    pub responses: Vec<Frame<Response>>,
+}
+
+impl Exchange {
+    /// Time to the first response, captures only
+    pub fn latency(&self) -> Option<Duration> {
+        let sent = self.request.timestamp?;
+        let received = self.responses.first()?.timestamp?;
+        Some(received.saturating_sub(sent))
+    }
+}
+
+/// Result of analysis, `Display` prints a human readable report
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    /// In order of requests
+    pub exchanges: Vec<Exchange>,
+    /// Responses with id of no request
+    pub orphans: Vec<Frame<Response>>,
+    pub skipped: Vec<Skipped>,
+    /// Answers slower than this are reported as late
+    pub timeout: Option<Duration>,
+}
+
+impl Analysis {
+    /// Analyze raw dumps of host -> device (`requests`) and device -> host (`responses`) bytes
+    ///
+    /// Frames with longer body than `max_frame_length` are skipped as the codec with the same
+    /// limit does.
+    pub fn from_raw(requests: &[u8], responses: &[u8], max_frame_length: usize) -> Self {
+        let mut skipped = vec![];
+        let requests = split(requests, Direction::Request, max_frame_length, &mut skipped);
+        let responses = split(
+            responses,
+            Direction::Response,
+            max_frame_length,
+            &mut skipped,
+        );
+        Self::pair(requests, responses, skipped)
+    }
+
+    /// Analyze [`Capture`], answers slower than `timeout` are reported as late
+    ///
+    /// Capture holds decoded messages only, so corrupted frames and garbage are not seen.
+    pub fn from_capture(capture: &Capture, timeout: Option<Duration>) -> Self {
+        let mut requests = vec![];
+        let mut responses = vec![];
+        let mut skipped = vec![];
+
+        for (offset, record) in capture.records.iter().enumerate() {
+            let timestamp = Some(record.timestamp);
+            let decoded = match record.direction {
+                Direction::Request => record.decode_request().map(|message| {
+                    requests.push(Frame {
+                        offset,
+                        timestamp,
+                        message,
+                    })
+                }),
+                Direction::Response => record.decode_response().map(|message| {
+                    responses.push(Frame {
+                        offset,
+                        timestamp,
+                        message,
+                    })
+                }),
+            };
+            if decoded.is_err() {
+                skipped.push(Skipped {
+                    direction: record.direction,
+                    offset,
+                    len: record.frame.len(),
+                    reason: SkipReason::Undecodable,
+                });
+            }
+        }
+
+        Self {
+            timeout,
+            ..Self::pair(requests, responses, skipped)
+        }
+    }
+
+    /// Responses go to the first unanswered request with the same id, repeated answers to
+    /// the last answered one (ids restart from 1 with every host process)
+    fn pair(
+        requests: Vec<Frame<Request>>,
+        responses: Vec<Frame<Response>>,
+        skipped: Vec<Skipped>,
+    ) -> Self {
+        let mut by_id: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (i, req) in requests.iter().enumerate() {
+            by_id.entry(req.message.id).or_default().push(i);
+        }
+
+        let mut exchanges: Vec<Exchange> = requests
+            .into_iter()
+            .map(|request| Exchange {
+                request,
+                responses: vec![],
+            })
+            .collect();
+        let mut orphans = vec![];
+
+        for resp in responses {
+            let candidates = by_id.get(&resp.message.id).map(Vec::as_slice);
+            let target = candidates.and_then(|c| {
+                c.iter()
+                    .copied()
+                    .find(|i| exchanges[*i].responses.is_empty())
+                    .or_else(|| c.last().copied())
+            });
+            match target {
+                Some(i) => exchanges[i].responses.push(resp),
+                None => orphans.push(resp),
+            }
+        }
+
+        Self {
+            exchanges,
+            orphans,
+            skipped,
+            timeout: None,
+        }
+    }
+
+    pub fn unanswered(&self) -> impl Iterator<Item = &Exchange> {
+        self.exchanges.iter().filter(|e| e.responses.is_empty())
+    }
+
+    /// Answered slower than [`Analysis::timeout`]
+    pub fn late(&self) -> impl Iterator<Item = &Exchange> {
+        self.exchanges
+            .iter()
+            .filter(|e| matches!((e.latency(), self.timeout), (Some(l), Some(t)) if l > t))
+    }
+
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped.iter().map(|s| s.len).sum()
+    }
+}
+
+/// Split `data` into frames the same way the codec does, recording everything skipped
+fn split<M: Message + Default>(
+    data: &[u8],
+    direction: Direction,
+    max_frame_length: usize,
+    skipped: &mut Vec<Skipped>,
+) -> Vec<Frame<M>> {
+    let mut skip = |offset: usize, len: usize, reason: SkipReason| match skipped.last_mut() {
+        Some(last)
+            if last.direction == direction
+                && last.reason == reason
+                && last.offset + last.len == offset =>
+        {
+            last.len += len
+        }
+        _ => skipped.push(Skipped {
+            direction,
+            offset,
+            len,
+            reason,
+        }),
+    };
+
+    let mut frames = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let magick = match data[pos..].iter().position(|b| *b == Info::Magick as u8) {
+            Some(0) => pos,
+            Some(n) => {
+                skip(pos, n, SkipReason::Garbage);
+                pos + n
+            }
+            None => {
+                skip(pos, data.len() - pos, SkipReason::Garbage);
+                break;
+            }
+        };
+
+        let frame_len = match parse_header(&data[magick..]) {
+            FrameHeader::Valid {
+                header_len,
+                body_len,
+            } if body_len <= max_frame_length as u64 => header_len + body_len as usize,
+            FrameHeader::Incomplete => {
+                skip(magick, data.len() - magick, SkipReason::Truncated);
+                break;
+            }
+            _ => {
+                skip(magick, 1, SkipReason::InvalidLength);
+                pos = magick + 1;
+                continue;
+            }
+        };
+        if data.len() - magick < frame_len {
+            skip(magick, data.len() - magick, SkipReason::Truncated);
+            break;
+        }
+
+        // like the codec, an undecodable frame drops only its magick
+        match M::decode_length_delimited(&data[magick + 1..magick + frame_len]) {
+            Ok(message) => {
+                frames.push(Frame {
+                    offset: magick,
+                    timestamp: None,
+                    message,
+                });
+                pos = magick + frame_len;
+            }
+            Err(_) => {
+                skip(magick, 1, SkipReason::Undecodable);
+                pos = magick + 1;
+            }
+        }
+    }
+    frames
+}
+
+fn enum_name<T: fmt::Debug>(value: i32, from_i32: fn(i32) -> Option<T>) -> String {
+    from_i32(value).map_or_else(|| format!("?{}", value), |v| format!("{:?}", v))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn position<M>(frame: &Frame<M>) -> String {
+    match frame.timestamp {
+        Some(t) => format!("{:>12.3} ms", t.as_secs_f64() * 1000.0),
+        None => format!("@0x{:08X}", frame.offset),
+    }
+}
+
+fn describe_request(req: &Request) -> String {
+    let mut parts = vec![];
+
+    if let Some(ctrl) = &req.control {
+        let mut control = vec![];
+        if let Some(v) = ctrl.valve_state {
+            control.push(format!("valve={}", enum_name(v, ValveState::from_i32)));
+        }
+        if let Some(c) = ctrl.actuator_state {
+            control.push(format!("camera={}", enum_name(c, ActuatorState::from_i32)));
+        }
+        if let Some(ch) = ctrl.select_channel {
+            control.push(format!("channel={}", ch));
+        }
+        if control.is_empty() {
+            control.push("read".to_owned());
+        }
+        parts.push(format!("control {}", control.join(" ")));
+    }
+
+    match req.i2c.as_ref().and_then(|i| i.request.as_ref()) {
+        Some(i2c_request::Request::Enumerate(_)) => parts.push("I2C enumerate".to_owned()),
+        Some(i2c_request::Request::Sequence(seq)) => {
+            let ops = seq
+                .operations
+                .iter()
+                .map(|op| match &op.operation {
+                    Some(i2c_operation::Operation::Write(w)) => format!("W[{}]", hex(&w.data)),
+                    Some(i2c_operation::Operation::Read(r)) => format!("R({})", r.length),
+                    None => "?".to_owned(),
+                })
+                .collect::<Vec<_>>();
+            parts.push(format!(
+                "I2C bus {} addr 0x{:02X}: {}",
+                seq.bus,
+                seq.address,
+                ops.join(", ")
+            ))
+        }
+        None => {}
+    }
+
+    if parts.is_empty() {
+        "empty".to_owned()
+    } else {
+        parts.join("; ")
+    }
+}
+
+fn describe_response(resp: &Response) -> String {
+    let mut parts = vec![format!(
+        "status={}",
+        enum_name(resp.global_status, Status::from_i32)
+    )];
+
+    if let Some(ctrl) = &resp.control {
+        parts.push(format!(
+            "control valve={} camera={} channel={}",
+            enum_name(ctrl.valve_state, ValveState::from_i32),
+            enum_name(ctrl.actuator_state, ActuatorState::from_i32),
+            ctrl.selected_channel
+        ));
+    }
+
+    match resp.i2c.as_ref().and_then(|i| i.response.as_ref()) {
+        Some(i2c_response::Response::Enumerate(list)) => parts.push(format!(
+            "I2C buses {}",
+            list.buses
+                .iter()
+                .map(|b| format!("{} ({} Hz)", b.bus, b.max_speed))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        Some(i2c_response::Response::Sequence(seq)) => {
+            let ops = seq
+                .operations
+                .iter()
+                .map(|op| match &op.operation {
+                    Some(i2c_result::Operation::Write(status)) => {
+                        format!("W {}", enum_name(*status, I2cResultCode::from_i32))
+                    }
+                    Some(i2c_result::Operation::Read(r)) => format!(
+                        "R {} [{}]",
+                        enum_name(r.status, I2cResultCode::from_i32),
+                        hex(&r.data)
+                    ),
+                    None => "?".to_owned(),
+                })
+                .collect::<Vec<_>>();
+            parts.push(format!(
+                "I2C bus {} addr 0x{:02X}: {}",
+                seq.bus,
+                seq.address,
+                ops.join(", ")
+            ))
+        }
+        None => {}
+    }
+
+    parts.join("; ")
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for e in &self.exchanges {
+            let req = &e.request;
+            writeln!(
+                f,
+                "{} REQ id={} device=0x{:04X} protocol={}: {}",
+                position(req),
+                req.message.id,
+                req.message.device_id,
+                req.message.protocol_version,
+                describe_request(&req.message)
+            )?;
+
+            if e.responses.is_empty() {
+                writeln!(f, "    !! no answer (timeout)")?;
+            }
+            for (n, resp) in e.responses.iter().enumerate() {
+                writeln!(
+                    f,
+                    "{} RSP id={} device=0x{:04X} protocol={}: {}",
+                    position(resp),
+                    resp.message.id,
+                    resp.message.device_id,
+                    resp.message.protocol_version,
+                    describe_response(&resp.message)
+                )?;
+                if n > 0 {
+                    writeln!(f, "    !! repeated answer")?;
+                }
+            }
+
+            if let (Some(latency), Some(timeout)) = (e.latency(), self.timeout) {
+                if latency > timeout {
+                    writeln!(
+                        f,
+                        "    !! late answer: {:.3} ms",
+                        latency.as_secs_f64() * 1000.0
+                    )?;
+                }
+            }
+        }
+
+        for resp in &self.orphans {
+            writeln!(
+                f,
+                "{} RSP id={}: {}",
+                position(resp),
+                resp.message.id,
+                describe_response(&resp.message)
+            )?;
+            writeln!(f, "    !! orphan: no request with this id")?;
+        }
+
+        for s in &self.skipped {
+            writeln!(
+                f,
+                "@0x{:08X} {:?}: {} bytes skipped ({:?})",
+                s.offset, s.direction, s.len, s.reason
+            )?;
+        }
+
+        writeln!(
+            f,
+            "{} requests, {} unanswered, {} late, {} orphan responses, {} bytes skipped",
+            self.exchanges.len(),
+            self.unanswered().count(),
+            self.late().count(),
+            self.orphans.len(),
+            self.skipped_bytes()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::encoding::encode_varint;
+
+    use super::*;
+    use crate::DEFAULT_MAX_FRAME_LENGTH;
+
+    const MAGICK: u8 = Info::Magick as u8;
+
+    fn frame(message: impl Message) -> Vec<u8> {
+        let mut frame = vec![MAGICK];
+        message.encode_length_delimited(&mut frame).unwrap();
+        frame
+    }
+
+    fn request(id: u32) -> Vec<u8> {
+        frame(Request {
+            id,
+            ..Default::default()
+        })
+    }
+
+    fn response(id: u32) -> Vec<u8> {
+        frame(Response {
+            id,
+            ..Default::default()
+        })
+    }
+
+    fn skip(offset: usize, len: usize, reason: SkipReason) -> Skipped {
+        Skipped {
+            direction: Direction::Request,
+            offset,
+            len,
+            reason,
+        }
+    }
+
+    #[test]
+    fn pairing() {
+        // ids restart with a new host process
+        let requests = [request(1), request(2), request(1)].concat();
+        let responses = [
+            response(1),
+            response(1),
+            response(2),
+            response(1),
+            response(3),
+        ]
+        .concat();
+
+        let analysis = Analysis::from_raw(&requests, &responses, DEFAULT_MAX_FRAME_LENGTH);
+
+        let answers: Vec<_> = analysis
+            .exchanges
+            .iter()
+            .map(|e| e.responses.len())
+            .collect();
+        // the second id 1 answers the unanswered request, the repeat goes to the last one
+        assert_eq!(answers, [1, 1, 2]);
+        assert_eq!(analysis.orphans.len(), 1);
+        assert_eq!(analysis.orphans[0].message.id, 3);
+        assert!(analysis.skipped.is_empty());
+    }
+
+    #[test]
+    fn skipped_ranges() {
+        let valid = request(1);
+        let undecodable = [MAGICK, 2, 0xFF, 0xFF];
+        let invalid = [
+            MAGICK, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let truncated = [MAGICK, 5, 0x08];
+        let dump = [
+            &[0x00, 0x00][..],
+            &valid,
+            &undecodable,
+            &invalid,
+            &truncated,
+        ]
+        .concat();
+
+        let analysis = Analysis::from_raw(&dump, &[], DEFAULT_MAX_FRAME_LENGTH);
+
+        assert_eq!(analysis.exchanges.len(), 1);
+        let undecodable_at = 2 + valid.len();
+        let invalid_at = undecodable_at + undecodable.len();
+        let truncated_at = invalid_at + invalid.len();
+        assert_eq!(
+            analysis.skipped,
+            [
+                skip(0, 2, SkipReason::Garbage),
+                // only magick is dropped, the rest is scanned again
+                skip(undecodable_at, 1, SkipReason::Undecodable),
+                skip(undecodable_at + 1, 3, SkipReason::Garbage),
+                skip(invalid_at, 1, SkipReason::InvalidLength),
+                skip(invalid_at + 1, 10, SkipReason::Garbage),
+                skip(truncated_at, 3, SkipReason::Truncated),
+            ]
+        );
+        assert_eq!(analysis.skipped_bytes(), dump.len() - valid.len());
+    }
+
+    #[test]
+    fn adjacent_skips_merged() {
+        // length prefix of the first frame is the next magick, both exceed the limit
+        let dump = [MAGICK, MAGICK, 5];
+
+        let analysis = Analysis::from_raw(&dump, &[], 4);
+
+        assert_eq!(
+            analysis.skipped,
+            [
+                skip(0, 2, SkipReason::InvalidLength),
+                skip(2, 1, SkipReason::Garbage),
+            ]
+        );
+    }
+
+    #[test]
+    fn frame_length_limit() {
+        // request with a long unknown field, skipped by the decoder
+        let mut body = vec![];
+        encode_varint((15000 << 3) | 2, &mut body);
+        encode_varint(5000, &mut body);
+        body.resize(body.len() + 5000, 0);
+        let mut dump = vec![MAGICK];
+        encode_varint(body.len() as u64, &mut dump);
+        dump.extend(body);
+
+        let analysis = Analysis::from_raw(&dump, &[], DEFAULT_MAX_FRAME_LENGTH);
+        assert!(analysis.exchanges.is_empty());
+        assert_eq!(analysis.skipped[0].reason, SkipReason::InvalidLength);
+
+        let analysis = Analysis::from_raw(&dump, &[], 8192);
+        assert_eq!(analysis.exchanges.len(), 1);
+        assert!(analysis.skipped.is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+
+use laser_setup_interface::analyzer::Analysis;
+use laser_setup_interface::capture::Capture;
+use laser_setup_interface::DEFAULT_MAX_FRAME_LENGTH;
+
+/// Decode and check Laser-setup traffic dumps
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Raw dump of host -> device bytes
+    #[clap(short, long)]
+    requests: Option<PathBuf>,
+
+    /// Raw dump of device -> host bytes
+    #[clap(short('s'), long)]
+    responses: Option<PathBuf>,
+
+    /// Capture written by `capture::Recorder`, raw dumps are ignored. Has no corrupted
+    /// frames or garbage, they are dropped before recording
+    #[clap(short, long)]
+    capture: Option<PathBuf>,
+
+    /// Report answers slower than this (captures only), ms
+    #[clap(short, long, default_value = "100")]
+    timeout: u64,
+
+    /// Longest frame body accepted by the host when the dump was taken (raw dumps only)
+    #[clap(short, long, default_value_t = DEFAULT_MAX_FRAME_LENGTH)]
+    max_frame_length: usize,
+}
+
+fn read(path: &Option<PathBuf>) -> std::io::Result<Vec<u8>> {
+    path.as_ref().map_or(Ok(vec![]), std::fs::read)
+}
+
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+
+    let args = Cli::parse();
+
+    let analysis = if let Some(capture) = &args.capture {
+        Analysis::from_capture(
+            &Capture::load(capture)?,
+            Some(Duration::from_millis(args.timeout)),
+        )
+    } else {
+        Analysis::from_raw(
+            &read(&args.requests)?,
+            &read(&args.responses)?,
+            args.max_frame_length,
+        )
+    };
+
+    print!("{}", analysis);
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use clap::Parser;
+
+use laser_setup_interface::mock::{Eeprom24, FreqMeter, MockDevice};
+
+/// Virtual Laser-setup on a pseudo-terminal
+#[derive(Parser, Debug)]
+struct Cli {
+    /// I2C bus as `id:speed`, may be repeated
+    #[clap(short('B'), long("bus"), value_parser = parse_bus, default_values = ["0:100000", "1:400000"])]
+    buses: Vec<(u32, u32)>,
+
+    /// Frequency reported by the frequency meter at address 0x0B of every bus, Hz
+    #[clap(short, long, default_value = "32768")]
+    freq: f32,
+
+    /// Attach 24C32 EEPROM at this address of every bus, decimal or `0x` hex
+    #[clap(short, long, value_parser = parse_address)]
+    eeprom: Option<u8>,
+
+    /// Create symlink to the pseudo-terminal at this path
+    #[clap(short, long)]
+    link: Option<std::path::PathBuf>,
+}
+
+fn parse_bus(s: &str) -> Result<(u32, u32), String> {
+    let (id, speed) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected `id:speed`, got `{}`", s))?;
+    Ok((
+        id.parse().map_err(|e| format!("bus id: {}", e))?,
+        speed.parse().map_err(|e| format!("bus speed: {}", e))?,
+    ))
+}
+
+fn parse_address(s: &str) -> Result<u8, String> {
+    let address = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("I2C address: {}", e))?;
+    if address > 0x7F {
+        return Err(format!("I2C address 0x{:02x} is not 7-bit", address));
+    }
+    Ok(address)
+}
+
+/// Remove symlink at `path` if there is one, refuse to remove anything else
+#[cfg(unix)]
+fn remove_link(path: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a symlink", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), laser_setup_interface::Error> {
+    use tokio_serial::SerialPort;
+
+    env_logger::init();
+
+    let args = Cli::parse();
+
+    let device = MockDevice::new();
+    for (id, speed) in &args.buses {
+        device.add_i2c_bus(*id, *speed);
+        device.attach_i2c_device(*id, FreqMeter::ADDRESS, FreqMeter::new(args.freq));
+        if let Some(addr) = args.eeprom {
+            device.attach_i2c_device(*id, addr, Eeprom24::c32());
+        }
+    }
+
+    // slave end is kept open, so master does not hang up when a client disconnects
+    let (master, slave) = tokio_serial::SerialStream::pair()?;
+    let slave_path = slave.name().expect("pseudo-terminal has no name");
+
+    if let Some(link) = &args.link {
+        remove_link(link)?;
+        std::os::unix::fs::symlink(&slave_path, link)?;
+        println!("{} -> {}", link.display(), slave_path);
+    } else {
+        println!("{}", slave_path);
+    }
+
+    // held slave end keeps the device running until interrupted
+    tokio::select! {
+        res = device.serve(master) => {
+            if let Err(e) = res {
+                log::error!("Simulator stopped: {}", e);
+            }
+        }
+        res = tokio::signal::ctrl_c() => res?,
+    }
+
+    if let Some(link) = &args.link {
+        remove_link(link)?;
+    }
+    drop(slave);
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn main() {
+    let _ = Cli::parse();
+    eprintln!("laser-setup-sim requires pseudo-terminal support (Linux, macOS)");
+    std::process::exit(1);
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPort, SerialPortBuilderExt, StopBits};
+
+use crate::{Error, LaserSetup, DEFAULT_MAX_FRAME_LENGTH};
+
+/// Default serial port speed of the Laser-setup firmware
+pub const DEFAULT_BAUD_RATE: u32 = 1500000;
+
+/// Default timeout for control and I2C requests
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Fallible [`LaserSetup`] constructor with full serial port configuration
+#[derive(Debug, Clone)]
+pub struct LaserSetupBuilder {
+    port: String,
+    baud_rate: u32,
+    data_bits: DataBits,
+    parity: Parity,
+    stop_bits: StopBits,
+    flow_control: FlowControl,
+    dtr: Option<bool>,
+    rts: Option<bool>,
+
+    control_timeout: Duration,
+    i2c_timeout: Duration,
+    i2c_bus: u32,
+    max_frame_length: usize,
+    pipeline_window: usize,
+    max_i2c_payload: Option<usize>,
+}
+
+impl LaserSetupBuilder {
+    pub fn new(port: impl Into<String>) -> Self {
+        Self {
+            port: port.into(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+
+            control_timeout: DEFAULT_TIMEOUT,
+            i2c_timeout: DEFAULT_TIMEOUT,
+            i2c_bus: 0,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            pipeline_window: 1,
+            max_i2c_payload: None,
+        }
+    }
+
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn data_bits(mut self, data_bits: DataBits) -> Self {
+        self.data_bits = data_bits;
+        self
+    }
+
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.flow_control = flow_control;
+        self
+    }
+
+    /// DTR line level set right after the port is opened
+    pub fn dtr(mut self, level: bool) -> Self {
+        self.dtr = Some(level);
+        self
+    }
+
+    /// RTS line level set right after the port is opened
+    pub fn rts(mut self, level: bool) -> Self {
+        self.rts = Some(level);
+        self
+    }
+
+    /// Set both control and I2C timeouts
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.control_timeout(timeout).i2c_timeout(timeout)
+    }
+
+    /// Timeout for valve/camera/channel requests
+    pub fn control_timeout(mut self, timeout: Duration) -> Self {
+        self.control_timeout = timeout;
+        self
+    }
+
+    /// Timeout for I2C requests, long transfers may need more time
+    pub fn i2c_timeout(mut self, timeout: Duration) -> Self {
+        self.i2c_timeout = timeout;
+        self
+    }
+
+    /// I2C bus selected after connection, the first transaction fails with
+    /// [`Error::InvalidBus`] if the device has no such bus
+    pub fn i2c_bus(mut self, bus_id: u32) -> Self {
+        self.i2c_bus = bus_id;
+        self
+    }
+
+    /// Longest accepted response frame body, longer frames are dropped as corrupted
+    pub fn max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// See [`LaserSetup::set_pipeline_window`]
+    pub fn pipeline_window(mut self, window: usize) -> Self {
+        self.pipeline_window = window;
+        self
+    }
+
+    /// See [`LaserSetup::set_max_i2c_payload`]
+    pub fn max_i2c_payload(mut self, max: usize) -> Self {
+        self.max_i2c_payload = Some(max);
+        self
+    }
+
+    /// Open serial port with configured settings
+    pub fn open(self) -> Result<LaserSetup, Error> {
+        let mut port = tokio_serial::new(&self.port, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            .open_native_async()?;
+
+        if let Some(dtr) = self.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = self.rts {
+            port.write_request_to_send(rts)?;
+        }
+
+        Ok(self.build(port))
+    }
+
+    /// Use already opened byte stream, serial port settings are ignored
+    pub fn build<IO: AsyncRead + AsyncWrite + Unpin>(self, io: IO) -> LaserSetup<IO> {
+        let mut res = LaserSetup::from_io(io, self.control_timeout);
+        res.set_i2c_timeout(self.i2c_timeout);
+        // checked by the first transaction, buses are enumerated there
+        res.selected_i2c_bus = self.i2c_bus;
+        res.set_max_frame_length(self.max_frame_length);
+        res.set_pipeline_window(self.pipeline_window);
+        res.set_max_i2c_payload(self.max_i2c_payload);
+        res
+    }
+}
@@ -0,0 +1,383 @@
+//! Traffic capture and replay
+//!
+//! [`Recorder`] attached with [`set_recorder`](crate::LaserSetup::set_recorder) logs every
+//! sent request and received response to a text file, one frame per line:
+//!
+//! ```text
+//! # laser-setup capture v1
+//! # start 1700000000000000
+//! 0 REQ 5a0608011085a00118...
+//! 1532 RSP 5a0a0801108...
+//! ```
+//!
+//! Timestamps are microseconds from `start` (unix time, microseconds), frames are hex with
+//! `Info::Magick` and length prefix. [`Capture::write_pcapng`] converts a capture for
+//! Wireshark, [`Replay`] plays it back as if it were the device.
+//!
+//! Frames are re-encoded from decoded messages, not copied from the line: garbage bytes
+//! and frames dropped by the codec never reach the capture. Use raw dumps of the serial
+//! line to analyze them, see [`analyzer`](crate::analyzer).
+
+mod pcapng;
+mod replay;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use prost::Message;
+use tokio_util::codec::Encoder;
+
+use crate::protobuf::messages::{Request, Response};
+use crate::protobuf::protobuf_md_codec::{ClientCodec, DeviceCodec};
+
+pub use replay::Replay;
+
+const HEADER: &str = "# laser-setup capture v1";
+const START_PREFIX: &str = "# start ";
+
+/// Who sent the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Host to device
+    Request,
+    /// Device to host
+    Response,
+}
+
+impl Direction {
+    fn tag(self) -> &'static str {
+        match self {
+            Direction::Request => "REQ",
+            Direction::Response => "RSP",
+        }
+    }
+}
+
+/// One captured frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the start of capture
+    pub timestamp: Duration,
+    pub direction: Direction,
+    /// Whole frame: `Info::Magick`, length prefix and message
+    pub frame: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn decode_request(&self) -> Result<Request, crate::Error> {
+        Ok(Request::decode_length_delimited(self.body())?)
+    }
+
+    pub fn decode_response(&self) -> Result<Response, crate::Error> {
+        Ok(Response::decode_length_delimited(self.body())?)
+    }
+
+    fn body(&self) -> &[u8] {
+        self.frame.get(1..).unwrap_or_default()
+    }
+}
+
+/// Capture file loaded into memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// Wall clock time of the start of capture
+    pub start: SystemTime,
+    pub records: Vec<CaptureRecord>,
+}
+
+impl Capture {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from(reader: impl BufRead) -> io::Result<Self> {
+        let mut start = UNIX_EPOCH;
+        let mut records = vec![];
+
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let invalid = |what: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("capture line {}: {}", n + 1, what),
+                )
+            };
+
+            if let Some(us) = line.strip_prefix(START_PREFIX) {
+                let us = us.parse().map_err(|_| invalid("invalid start time"))?;
+                start = UNIX_EPOCH + Duration::from_micros(us);
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(timestamp), Some(direction), Some(frame), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("expected '<timestamp> <REQ|RSP> <hex>'"));
+            };
+
+            records.push(CaptureRecord {
+                timestamp: Duration::from_micros(
+                    timestamp
+                        .parse()
+                        .map_err(|_| invalid("invalid timestamp"))?,
+                ),
+                direction: match direction {
+                    "REQ" => Direction::Request,
+                    "RSP" => Direction::Response,
+                    _ => return Err(invalid("unknown direction")),
+                },
+                frame: from_hex(frame).ok_or_else(|| invalid("invalid hex"))?,
+            });
+        }
+
+        Ok(Self { start, records })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        write_header(&mut writer, self.start)?;
+        for record in &self.records {
+            write_record(&mut writer, record)?;
+        }
+        Ok(())
+    }
+
+    /// Export to pcapng, frames are stored as `LINKTYPE_USER0` packets with direction flags
+    pub fn write_pcapng(&self, writer: impl Write) -> io::Result<()> {
+        pcapng::write(writer, self)
+    }
+}
+
+/// Logs traffic of [`LaserSetup`](crate::LaserSetup) to a capture file, see [module docs](self)
+///
+/// Write errors are logged and do not interrupt communication.
+pub struct Recorder {
+    started: Instant,
+    sink: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    /// Start capture to `sink`
+    pub fn new(sink: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut sink: Box<dyn Write + Send> = Box::new(sink);
+        write_header(&mut sink, SystemTime::now())?;
+        sink.flush()?;
+        Ok(Self {
+            started: Instant::now(),
+            sink,
+        })
+    }
+
+    /// Start capture to a new file at `path`
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    pub(crate) fn request(&mut self, req: &Request) {
+        let mut frame = BytesMut::new();
+        let res = ClientCodec::new().encode(req.clone(), &mut frame);
+        self.record(Direction::Request, res.map(|_| frame))
+    }
+
+    pub(crate) fn response(&mut self, resp: &Response) {
+        let mut frame = BytesMut::new();
+        let res = DeviceCodec::new().encode(resp.clone(), &mut frame);
+        self.record(Direction::Response, res.map(|_| frame))
+    }
+
+    fn record(&mut self, direction: Direction, frame: Result<BytesMut, crate::Error>) {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::warn!("Failed to encode captured frame: {}", e);
+                return;
+            }
+        };
+
+        let record = CaptureRecord {
+            timestamp: self.started.elapsed(),
+            direction,
+            frame: frame.to_vec(),
+        };
+        // flush every frame: capture must survive the crash it is meant to explain
+        if let Err(e) = write_record(&mut self.sink, &record).and_then(|_| self.sink.flush()) {
+            log::warn!("Failed to write capture: {}", e);
+        }
+    }
+}
+
+fn write_header(writer: &mut impl Write, start: SystemTime) -> io::Result<()> {
+    let start = start.duration_since(UNIX_EPOCH).unwrap_or_default();
+    writeln!(writer, "{}", HEADER)?;
+    writeln!(writer, "{}{}", START_PREFIX, start.as_micros())
+}
+
+fn write_record(writer: &mut impl Write, record: &CaptureRecord) -> io::Result<()> {
+    write!(
+        writer,
+        "{} {} ",
+        record.timestamp.as_micros(),
+        record.direction.tag()
+    )?;
+    for b in &record.frame {
+        write!(writer, "{:02x}", b)?;
+    }
+    writeln!(writer)
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| match c {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::mock::MockDevice;
+    use crate::{CurrentControlState, Error};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Capture sink readable while the recorder owns it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn channel(channel: u32) -> CurrentControlState {
+        CurrentControlState {
+            channel,
+            ..Default::default()
+        }
+    }
+
+    /// Two reads answered with channels 1 and 2
+    async fn record(device: &MockDevice) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut laser = device.connect(TIMEOUT);
+        laser.set_recorder(Some(Recorder::new(buffer.clone()).unwrap()));
+
+        device.set_control_state(channel(1));
+        laser.read().await.unwrap();
+        device.set_control_state(channel(2));
+        laser.read().await.unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn recorder_round_trip() {
+        let device = MockDevice::new();
+        let bytes = record(&device).await;
+        let capture = Capture::read_from(&bytes[..]).unwrap();
+
+        let directions: Vec<_> = capture.records.iter().map(|r| r.direction).collect();
+        assert_eq!(
+            directions,
+            [
+                Direction::Request,
+                Direction::Response,
+                Direction::Request,
+                Direction::Response
+            ]
+        );
+        let request = capture.records[0].decode_request().unwrap();
+        assert_eq!(request.id, device.received()[0].id);
+        assert_eq!(capture.records[1].decode_response().unwrap().id, request.id);
+        assert!(capture.records[2].timestamp >= capture.records[1].timestamp);
+
+        let mut written = vec![];
+        capture.write_to(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[tokio::test]
+    async fn replay_rewrites_ids_and_ends() {
+        let bytes = record(&MockDevice::new()).await;
+        let capture = Capture::read_from(&bytes[..]).unwrap();
+
+        // live request ids differ from the captured ones
+        let mut laser = Replay::new(capture).connect(TIMEOUT);
+        assert_eq!(laser.read().await.unwrap().channel, 1);
+        assert_eq!(laser.read().await.unwrap().channel, 2);
+        assert_eq!(laser.stale_frames(), 0);
+
+        let err = laser.read().await.unwrap_err();
+        assert!(matches!(err.root(), Error::UnexpectedEndOfStream));
+    }
+
+    #[tokio::test]
+    async fn strict_replay_stops_on_mismatch() {
+        let bytes = record(&MockDevice::new()).await;
+        let capture = Capture::read_from(&bytes[..]).unwrap();
+
+        let mut laser = Replay::new(capture).strict(true).connect(TIMEOUT);
+        let err = laser.enumerate_i2c_buses().await.unwrap_err();
+        assert!(matches!(err.root(), Error::UnexpectedEndOfStream));
+    }
+
+    #[test]
+    fn pcapng_blocks_aligned() {
+        let capture = Capture {
+            start: UNIX_EPOCH,
+            records: vec![CaptureRecord {
+                timestamp: Duration::from_micros(1),
+                direction: Direction::Response,
+                frame: vec![1, 2, 3],
+            }],
+        };
+        let mut out = vec![];
+        capture.write_pcapng(&mut out).unwrap();
+
+        let mut blocks = vec![];
+        let mut rest = &out[..];
+        while !rest.is_empty() {
+            let block_type = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            let trailer = u32::from_le_bytes(rest[len - 4..len].try_into().unwrap());
+            assert_eq!(trailer as usize, len);
+            blocks.push((block_type, rest[8..len - 4].to_vec()));
+            rest = &rest[len..];
+        }
+
+        assert_eq!(blocks.len(), 3);
+        let (block_type, epb) = &blocks[2];
+        assert_eq!(*block_type, 6);
+        // interface, timestamp, captured and original length, padded frame, flags, end
+        assert_eq!(epb.len(), 4 + 8 + 4 + 4 + 4 + 8 + 4);
+        assert_eq!(epb[12..20], [3, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(epb[20..24], [1, 2, 3, 0]);
+        assert_eq!(epb[24..32], [2, 0, 4, 0, 1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,75 @@
+//! Minimal pcapng writer: section header, one interface, enhanced packet blocks
+
+use std::io::{self, Write};
+use std::time::UNIX_EPOCH;
+
+use super::{Capture, Direction};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// DLT reserved for private use, Wireshark shows raw bytes unless a dissector is assigned
+const LINKTYPE_USER0: u16 = 147;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    // type + length + body + trailing length
+    let len = (12 + body.len()) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+pub(super) fn write(mut writer: impl Write, capture: &Capture) -> io::Result<()> {
+    let mut shb = vec![];
+    shb.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+    shb.extend(1u16.to_le_bytes()); // major version
+    shb.extend(0u16.to_le_bytes()); // minor version
+    shb.extend((-1i64).to_le_bytes()); // section length unknown
+    write_block(&mut writer, SECTION_HEADER_BLOCK, &shb)?;
+
+    let mut idb = vec![];
+    idb.extend(LINKTYPE_USER0.to_le_bytes());
+    idb.extend(0u16.to_le_bytes()); // reserved
+    idb.extend(0u32.to_le_bytes()); // no snap length limit
+    write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, &idb)?;
+
+    // default interface timestamp resolution is microseconds
+    let start = capture.start.duration_since(UNIX_EPOCH).unwrap_or_default();
+    for record in &capture.records {
+        let ts = (start + record.timestamp).as_micros() as u64;
+        let len = record.frame.len() as u32;
+
+        let mut epb = vec![];
+        epb.extend(0u32.to_le_bytes()); // interface id
+        epb.extend(((ts >> 32) as u32).to_le_bytes());
+        epb.extend((ts as u32).to_le_bytes());
+        epb.extend(len.to_le_bytes()); // captured length
+        epb.extend(len.to_le_bytes()); // original length
+        epb.extend(&record.frame);
+        epb.resize((epb.len() + 3) & !3, 0); // pad to 32 bits
+
+        epb.extend(OPT_EPB_FLAGS.to_le_bytes());
+        epb.extend(4u16.to_le_bytes());
+        epb.extend(
+            match record.direction {
+                Direction::Request => EPB_FLAGS_OUTBOUND,
+                Direction::Response => EPB_FLAGS_INBOUND,
+            }
+            .to_le_bytes(),
+        );
+        epb.extend(OPT_ENDOFOPT.to_le_bytes());
+        epb.extend(0u16.to_le_bytes());
+
+        write_block(&mut writer, ENHANCED_PACKET_BLOCK, &epb)?;
+    }
+
+    writer.flush()
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::codec::Decoder;
+
+use super::{Capture, Direction};
+use crate::protobuf::protobuf_md_codec::DeviceCodec;
+use crate::LaserSetup;
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Device side playing back a [`Capture`]
+///
+/// Every incoming request is answered with the responses that followed the next captured
+/// request. Request ids differ from run to run, so response ids are rewritten to the ids of
+/// the live requests; responses the device never sent in the capture become timeouts.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    capture: Capture,
+    realtime: bool,
+    strict: bool,
+}
+
+impl Replay {
+    pub fn new(capture: Capture) -> Self {
+        Self {
+            capture,
+            realtime: false,
+            strict: false,
+        }
+    }
+
+    /// Delay responses as in the capture instead of answering immediately
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Stop (close connection) when a request differs from the captured one
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Serve requests from `io` until it is closed or a request comes after the last
+    /// captured one, then the connection is closed
+    pub fn serve<IO>(self, io: IO) -> JoinHandle<()>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            if let Err(e) = self.run(io).await {
+                log::debug!("Replay stopped: {}", e);
+            }
+        })
+    }
+
+    /// Create [`LaserSetup`] connected to the replayed device through in-memory pipe
+    pub fn connect(self, timeout: Duration) -> LaserSetup<DuplexStream> {
+        let (client, device) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        self.serve(device);
+        LaserSetup::from_io(client, timeout)
+    }
+
+    async fn run<IO: AsyncRead + AsyncWrite + Unpin>(self, io: IO) -> Result<(), crate::Error> {
+        let mut io = DeviceCodec::new().framed(io);
+        let mut records = self.capture.records.into_iter().peekable();
+        // captured request id -> live request id
+        let mut ids = HashMap::new();
+
+        let mut index = 0;
+        while let Some(req) = io.next().await {
+            let req = req?;
+            let arrived = Instant::now();
+
+            let Some(captured) = records.find(|r| r.direction == Direction::Request) else {
+                log::warn!("Capture exhausted, request id={} not answered", req.id);
+                return Ok(());
+            };
+            let mut captured_req = captured.decode_request()?;
+            ids.insert(captured_req.id, req.id);
+
+            captured_req.id = req.id;
+            if captured_req != req {
+                log::warn!("Request {} differs from the captured one", index);
+                if self.strict {
+                    return Ok(());
+                }
+            }
+
+            while let Some(record) = records.next_if(|r| r.direction == Direction::Response) {
+                if self.realtime {
+                    let delay = record.timestamp.saturating_sub(captured.timestamp);
+                    tokio::time::sleep_until(arrived + delay).await;
+                }
+
+                let mut resp = record.decode_response()?;
+                if let Some(id) = ids.get(&resp.id) {
+                    resp.id = *id;
+                }
+                io.send(resp).await?;
+            }
+            index += 1;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+use std::time::Duration;
+
+use embedded_hal_async::i2c::{ErrorKind, Operation};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+use crate::i2c::results_to_error;
+use crate::{Error, I2cResultCode, LaserSetup};
+
+/// Delay between write attempts while the device is busy programming memory
+const ACK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Bytes of I2C sequence request or response besides the data
+const RESPONSE_OVERHEAD: usize = 64;
+
+/// Length of the first [`LaserSetup::discover_max_i2c_payload`] probe, doubled until refused
+const FIRST_PROBE_LENGTH: usize = 16;
+
+/// Assumed clock of buses not enumerated yet, Hz
+const STANDARD_MODE_SPEED: u32 = 100_000;
+
+/// Memory of an I2C device addressed by a big-endian offset written before data:
+/// EEPROM, FRAM, flash of a downstream MCU bootloader
+///
+/// Such transfers may be split into several transactions, each starting at its own offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Bytes of memory offset, 1..=4
+    pub address_width: usize,
+    /// Writes must not cross page boundaries, EEPROMs wrap inside the page; not zero
+    pub page_size: Option<usize>,
+    /// How long the device may NAK after a write while programming memory
+    pub write_cycle: Duration,
+}
+
+impl MemoryLayout {
+    /// 24Cxx EEPROM, 1-byte offset up to 256 bytes, 2-byte for larger chips
+    pub fn eeprom(address_width: usize, page_size: usize) -> Self {
+        Self {
+            address_width,
+            page_size: Some(page_size),
+            write_cycle: Duration::from_millis(10),
+        }
+    }
+
+    /// Fail with [`Error::InvalidMemoryLayout`] if offset width or page size can't be used
+    fn validate(&self) -> Result<(), Error> {
+        if (1..=4).contains(&self.address_width) && self.page_size != Some(0) {
+            Ok(())
+        } else {
+            Err(Error::InvalidMemoryLayout {
+                address_width: self.address_width,
+                page_size: self.page_size,
+            })
+        }
+    }
+
+    /// Check that the layout is valid and `len` bytes from `offset` are addressable
+    fn check_range(&self, offset: u32, len: usize) -> Result<(), Error> {
+        self.validate()?;
+        let capacity = 1u64 << (8 * self.address_width);
+        let end = offset as u64 + len as u64;
+        if end > capacity {
+            Err(Error::MemoryOverflow { end, capacity })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn offset_bytes(&self, offset: usize) -> Vec<u8> {
+        (offset as u32).to_be_bytes()[4 - self.address_width..].to_vec()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> LaserSetup<IO> {
+    /// Longest I2C operation the firmware accepts, longer ones fail with
+    /// [`Error::I2cPayloadTooLong`] without a request to the device. `None` (default) is
+    /// unknown, see [`LaserSetup::discover_max_i2c_payload`]
+    pub fn set_max_i2c_payload(&mut self, max: Option<usize>) {
+        self.max_i2c_payload = max;
+    }
+
+    pub fn max_i2c_payload(&self) -> Option<usize> {
+        self.max_i2c_payload
+    }
+
+    /// Find the longest read the firmware accepts and remember it
+    ///
+    /// Reads from `address` of growing length are sent until the firmware answers
+    /// `I2cTooLongData`, then the limit is narrowed by binary search. The device must
+    /// acknowledge reads and be harmless to read from (not a FIFO), any I2C error stops
+    /// the discovery. Answer timeout grows with the probe length.
+    pub async fn discover_max_i2c_payload(
+        &mut self,
+        bus: u32,
+        address: u8,
+    ) -> Result<usize, Error> {
+        let previous = self.max_i2c_payload.take();
+        let res = self.probe_max_i2c_payload(bus, address).await;
+        self.max_i2c_payload = res.as_ref().ok().copied().or(previous);
+        res
+    }
+
+    async fn probe_max_i2c_payload(&mut self, bus: u32, address: u8) -> Result<usize, Error> {
+        let limit = self.max_chunk_length();
+        let mut buf = vec![0u8; limit];
+
+        // largest accepted and smallest refused lengths
+        let (mut fits, mut too_long) = (0, limit + 1);
+        let mut len = FIRST_PROBE_LENGTH.min(limit);
+        while too_long - fits > 1 {
+            let timeout = self.transfer_timeout(bus, len);
+            let ops = &mut [Operation::Read(&mut buf[..len])];
+            let results = self.sequence_exchange(bus, address, ops, timeout).await?;
+            if results[0].code == Some(I2cResultCode::I2cTooLongData) {
+                too_long = len;
+            } else {
+                results_to_error(ops, &results)?;
+                fits = len;
+            }
+
+            len = if too_long > limit {
+                (fits * 2).min(limit)
+            } else {
+                (fits + too_long) / 2
+            };
+        }
+
+        if fits == 0 {
+            // even a one-byte read is refused
+            return Err(Error::I2cPayloadTooLong {
+                index: 0,
+                length: 1,
+                max: 0,
+            });
+        }
+        log::debug!("Max I2C payload: {} bytes", fits);
+        Ok(fits)
+    }
+
+    /// Longest read whose answer fits into a response frame
+    fn max_chunk_length(&self) -> usize {
+        self.io
+            .codec()
+            .max_frame_length()
+            .saturating_sub(RESPONSE_OVERHEAD)
+            .max(1)
+    }
+
+    /// I2C timeout plus time to clock `len` bytes through `bus`
+    fn transfer_timeout(&self, bus: u32, len: usize) -> Duration {
+        let speed = self
+            .i2c_bus_info(bus)
+            .map_or(STANDARD_MODE_SPEED, |b| b.speed)
+            .max(1);
+        // 8 data bits and ACK per byte
+        self.i2c_timeout + Duration::from_micros(len as u64 * 9 * 1_000_000 / speed as u64)
+    }
+
+    /// Perform `operations`, retrying while the device does not acknowledge during
+    /// [`MemoryLayout::write_cycle`]: it does not answer while programming memory
+    async fn memory_transaction(
+        &mut self,
+        bus: u32,
+        address: u8,
+        layout: &MemoryLayout,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        let len = operations
+            .iter()
+            .map(|op| match op {
+                Operation::Write(w) => w.len(),
+                Operation::Read(r) => r.len(),
+            })
+            .sum();
+        let timeout = self.transfer_timeout(bus, len);
+
+        let started = Instant::now();
+        loop {
+            let res = match self
+                .sequence_exchange(bus, address, operations, timeout)
+                .await
+            {
+                Ok(results) => results_to_error(operations, &results),
+                Err(e) => Err(e),
+            };
+            match res {
+                Err(e)
+                    if matches!(e.root(), Error::I2C(ErrorKind::NoAcknowledge(_)))
+                        && started.elapsed() < layout.write_cycle =>
+                {
+                    tokio::time::sleep(ACK_POLL_INTERVAL).await
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Read `buf.len()` bytes of memory from `offset`, split by [`LaserSetup::max_i2c_payload`]
+    /// and response frame length
+    pub async fn read_memory_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        layout: &MemoryLayout,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        layout.check_range(offset, buf.len())?;
+        let chunk_size = self
+            .max_i2c_payload
+            .unwrap_or(usize::MAX)
+            .min(self.max_chunk_length())
+            .max(1);
+
+        for (n, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let start = layout.offset_bytes(offset as usize + n * chunk_size);
+            self.memory_transaction(
+                bus,
+                address,
+                layout,
+                &mut [Operation::Write(&start), Operation::Read(chunk)],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Write `data` to memory at `offset`, split by [`LaserSetup::max_i2c_payload`] and pages
+    ///
+    /// Every chunk is retried while the device is busy with a previous write, after the
+    /// last one the device is polled until it finishes programming, so the memory can be
+    /// accessed right away.
+    pub async fn write_memory_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        layout: &MemoryLayout,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        layout.check_range(offset, data.len())?;
+        let width = layout.address_width;
+        let max = self
+            .max_i2c_payload
+            .unwrap_or(usize::MAX)
+            .min(self.max_chunk_length());
+        if max <= width {
+            // not even one data byte fits after memory offset
+            return Err(Error::I2cPayloadTooLong {
+                index: 0,
+                length: width + 1,
+                max,
+            });
+        }
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let at = offset as usize + pos;
+            let page_left = layout.page_size.map_or(usize::MAX, |p| p - at % p);
+            let len = (max - width).min(page_left).min(data.len() - pos);
+
+            let mut chunk = layout.offset_bytes(at);
+            chunk.extend_from_slice(&data[pos..pos + len]);
+            self.memory_transaction(bus, address, layout, &mut [Operation::Write(&chunk)])
+                .await?;
+            pos += len;
+        }
+
+        if !data.is_empty() {
+            // acknowledged empty write means programming is over
+            self.memory_transaction(bus, address, layout, &mut [Operation::Write(&[])])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Eeprom24, I2cOp, MockDevice, RequestKind};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn device() -> MockDevice {
+        let device = MockDevice::new();
+        device.add_i2c_bus(0, 400_000);
+        device.attach_i2c_device(0, 0x50, Eeprom24::c02());
+        device
+    }
+
+    /// Operations of received I2C sequences
+    fn sequences(device: &MockDevice) -> Vec<Vec<I2cOp>> {
+        device
+            .received()
+            .into_iter()
+            .filter_map(|r| match r.kind {
+                RequestKind::I2cSequence { operations, .. } => Some(operations),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn write_split_by_pages() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+        let data: Vec<u8> = (1..=10).collect();
+
+        laser
+            .write_memory_on(0, 0x50, &MemoryLayout::eeprom(1, 8), 5, &data)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sequences(&device),
+            [
+                vec![I2cOp::Write(vec![5, 1, 2, 3])],
+                vec![I2cOp::Write(vec![8, 4, 5, 6, 7, 8, 9, 10])],
+                // polling for the end of write cycle
+                vec![I2cOp::Write(vec![])],
+            ]
+        );
+        assert_eq!(
+            device.with_i2c_device(0, 0x50, |e: &mut Eeprom24| e.memory()[5..15].to_vec()),
+            Some(data)
+        );
+    }
+
+    #[tokio::test]
+    async fn chunks_limited_by_payload() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+        laser.set_max_i2c_payload(Some(4));
+        let layout = MemoryLayout {
+            address_width: 1,
+            page_size: None,
+            write_cycle: Duration::ZERO,
+        };
+        let data: Vec<u8> = (1..=7).collect();
+
+        laser
+            .write_memory_on(0, 0x50, &layout, 0x10, &data)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 7];
+        laser
+            .read_memory_on(0, 0x50, &layout, 0x10, &mut buf)
+            .await
+            .unwrap();
+
+        assert_eq!(buf[..], data[..]);
+        assert_eq!(
+            sequences(&device),
+            [
+                vec![I2cOp::Write(vec![0x10, 1, 2, 3])],
+                vec![I2cOp::Write(vec![0x13, 4, 5, 6])],
+                vec![I2cOp::Write(vec![0x16, 7])],
+                vec![I2cOp::Write(vec![])],
+                vec![I2cOp::Write(vec![0x10]), I2cOp::Read(4)],
+                vec![I2cOp::Write(vec![0x14]), I2cOp::Read(3)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_layout() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+
+        for layout in [
+            MemoryLayout::eeprom(1, 0),
+            MemoryLayout::eeprom(0, 8),
+            MemoryLayout::eeprom(5, 8),
+        ] {
+            let err = laser
+                .write_memory_on(0, 0x50, &layout, 0, &[1])
+                .await
+                .unwrap_err();
+            assert!(matches!(err, Error::InvalidMemoryLayout { .. }));
+        }
+        assert!(sequences(&device).is_empty());
+    }
+
+    #[tokio::test]
+    async fn discover_payload() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+
+        device.set_max_i2c_payload(Some(100));
+        assert_eq!(laser.discover_max_i2c_payload(0, 0x50).await.unwrap(), 100);
+        assert_eq!(laser.max_i2c_payload(), Some(100));
+
+        // nothing fits, previous limit is kept
+        device.set_max_i2c_payload(Some(0));
+        let err = laser.discover_max_i2c_payload(0, 0x50).await.unwrap_err();
+        assert!(matches!(err, Error::I2cPayloadTooLong { max: 0, .. }));
+        assert_eq!(laser.max_i2c_payload(), Some(100));
+    }
+}
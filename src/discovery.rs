@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use tokio_serial::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::{Error, LaserSetupBuilder};
+
+/// USB properties a port must match to be probed, `None` matches anything
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+}
+
+impl DiscoveryFilter {
+    fn is_empty(&self) -> bool {
+        self.vid.is_none() && self.pid.is_none() && self.serial_number.is_none()
+    }
+
+    fn matches(&self, port: &SerialPortInfo) -> bool {
+        match &port.port_type {
+            SerialPortType::UsbPort(usb) => {
+                (self.vid.is_none() || self.vid == Some(usb.vid))
+                    && (self.pid.is_none() || self.pid == Some(usb.pid))
+                    && (self.serial_number.is_none() || self.serial_number == usb.serial_number)
+            }
+            // non-USB ports can't match any USB property
+            _ => self.is_empty(),
+        }
+    }
+}
+
+/// Laser-setup fixture found by [`discover`]
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    /// Port name to pass to [`LaserSetupBuilder::new`]
+    pub port: String,
+    /// USB adapter info, if port is USB
+    pub usb: Option<UsbPortInfo>,
+    pub device_id: u32,
+    pub protocol_version: u32,
+}
+
+/// Probe all serial ports matching `filter` at default settings and return ones with Laser-setup connected
+pub async fn discover(
+    filter: &DiscoveryFilter,
+    timeout: Duration,
+) -> Result<Vec<DiscoveredDevice>, Error> {
+    discover_with(filter, |port| LaserSetupBuilder::new(port).timeout(timeout)).await
+}
+
+/// Same as [`discover`], but port settings are provided by `builder` for each port name
+pub async fn discover_with(
+    filter: &DiscoveryFilter,
+    builder: impl Fn(&str) -> LaserSetupBuilder,
+) -> Result<Vec<DiscoveredDevice>, Error> {
+    let ports = tokio_serial::available_ports()?;
+
+    let probes = ports
+        .into_iter()
+        .filter(|p| filter.matches(p))
+        .map(|p| probe_port(p, &builder));
+
+    Ok(futures::future::join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+async fn probe_port(
+    port: SerialPortInfo,
+    builder: &impl Fn(&str) -> LaserSetupBuilder,
+) -> Option<DiscoveredDevice> {
+    let mut interface = match builder(&port.port_name).open() {
+        Ok(i) => i,
+        Err(e) => {
+            log::debug!("Skip port {}: {:?}", port.port_name, e);
+            return None;
+        }
+    };
+
+    match interface.handshake().await {
+        Ok(info) => {
+            log::info!("Laser-setup found on port {}", port.port_name);
+            Some(DiscoveredDevice {
+                usb: match port.port_type {
+                    SerialPortType::UsbPort(usb) => Some(usb),
+                    _ => None,
+                },
+                port: port.port_name,
+                device_id: info.device_id,
+                protocol_version: info.protocol_version,
+            })
+        }
+        Err(e) => {
+            match e.root() {
+                Error::IncompatibleDevice {
+                    device_id,
+                    protocol_version,
+                } => log::debug!(
+                    "Port {}: unknown device 0x{:04X}, protocol version {}",
+                    port.port_name,
+                    device_id,
+                    protocol_version
+                ),
+                _ => log::debug!("Port {}: no answer: {:?}", port.port_name, e),
+            }
+            None
+        }
+    }
+}
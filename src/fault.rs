@@ -0,0 +1,556 @@
+//! Fault-injection transport for testing behaviour on noisy links
+//!
+//! [`FaultInjector`] wraps the byte stream of [`LaserSetup`](crate::LaserSetup) and corrupts
+//! responses coming from the device frame by frame, requests are passed through untouched.
+//! Faults are taken from a script (one entry per frame) or generated from a seed, so every
+//! failing run can be reproduced:
+//!
+//! ```no_run
+//! # async fn test() -> Result<(), laser_setup_interface::Error> {
+//! use std::time::Duration;
+//! use laser_setup_interface::fault::{FaultConfig, FaultInjector, FrameFault};
+//! use laser_setup_interface::{mock::MockDevice, LaserSetup};
+//!
+//! let device = MockDevice::new();
+//! let (client, io) = tokio::io::duplex(64 * 1024);
+//! device.serve(io);
+//!
+//! let io = FaultInjector::scripted(
+//!     client,
+//!     vec![vec![FrameFault::Garbage(vec![0x00, 0xFF]), FrameFault::Split(3)]],
+//! );
+//! let mut laser = LaserSetup::from_io(io, Duration::from_millis(100));
+//! laser.read().await?;
+//!
+//! let (client, io) = tokio::io::duplex(64 * 1024);
+//! device.serve(io);
+//! let config = FaultConfig {
+//!     flip_bit: 0.1,
+//!     duplicate: 0.1,
+//!     ..Default::default()
+//! };
+//! let laser = LaserSetup::from_io(
+//!     FaultInjector::random(client, 42, config),
+//!     Duration::from_millis(100),
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::protobuf::messages::Info;
+use crate::protobuf::protobuf_md_codec::{parse_header, FrameHeader, DEFAULT_MAX_FRAME_LENGTH};
+
+/// Size of one read from the wrapped stream
+const READ_CHUNK_SIZE: usize = 1024;
+
+/// Corruption of one response frame, offsets are relative to the `Info::Magick` byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameFault {
+    /// Hold the frame back
+    Delay(Duration),
+    /// Remove byte at offset
+    DropByte(usize),
+    /// Invert `bit` (0..8) of byte at `offset`
+    FlipBit { offset: usize, bit: u8 },
+    /// Deliver the frame in two reads, the first one ends before offset
+    Split(usize),
+    /// Insert bytes before the frame
+    Garbage(Vec<u8>),
+    /// Deliver the frame twice
+    Duplicate,
+    /// Lose the whole frame
+    Drop,
+}
+
+/// Probabilities (0.0..=1.0) of every fault per frame for [`FaultInjector::random`]
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    pub drop_frame: f64,
+    pub delay: f64,
+    /// Upper limit of [`FrameFault::Delay`]
+    pub max_delay: Duration,
+    pub drop_byte: f64,
+    pub flip_bit: f64,
+    pub split: f64,
+    pub garbage: f64,
+    /// Upper limit of [`FrameFault::Garbage`] length
+    pub max_garbage: usize,
+    pub duplicate: f64,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            drop_frame: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(50),
+            drop_byte: 0.0,
+            flip_bit: 0.0,
+            split: 0.0,
+            garbage: 0.0,
+            max_garbage: 16,
+            duplicate: 0.0,
+        }
+    }
+}
+
+/// Number of injected faults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Frames seen from the wrapped stream
+    pub frames: u64,
+    pub dropped_frames: u64,
+    pub delayed_frames: u64,
+    pub dropped_bytes: u64,
+    pub flipped_bits: u64,
+    pub split_frames: u64,
+    pub garbage_bytes: u64,
+    pub duplicated_frames: u64,
+}
+
+enum FaultSource {
+    Script(VecDeque<Vec<FrameFault>>),
+    Random {
+        rng: Box<StdRng>,
+        config: FaultConfig,
+    },
+}
+
+impl FaultSource {
+    fn next(&mut self, frame_len: usize) -> Vec<FrameFault> {
+        match self {
+            FaultSource::Script(script) => script.pop_front().unwrap_or_default(),
+            FaultSource::Random { rng, config } => {
+                let mut faults = vec![];
+
+                if happens(rng, config.drop_frame) {
+                    faults.push(FrameFault::Drop);
+                }
+                if happens(rng, config.delay) {
+                    faults.push(FrameFault::Delay(Duration::from_micros(
+                        rng.gen_range(0..=config.max_delay.as_micros() as u64),
+                    )));
+                }
+                if happens(rng, config.drop_byte) {
+                    faults.push(FrameFault::DropByte(rng.gen_range(0..frame_len)));
+                }
+                if happens(rng, config.flip_bit) {
+                    faults.push(FrameFault::FlipBit {
+                        offset: rng.gen_range(0..frame_len),
+                        bit: rng.gen_range(0..8),
+                    });
+                }
+                if happens(rng, config.split) && frame_len > 1 {
+                    faults.push(FrameFault::Split(rng.gen_range(1..frame_len)));
+                }
+                if happens(rng, config.garbage) && config.max_garbage > 0 {
+                    let len = rng.gen_range(1..=config.max_garbage);
+                    faults.push(FrameFault::Garbage((0..len).map(|_| rng.gen()).collect()));
+                }
+                if happens(rng, config.duplicate) {
+                    faults.push(FrameFault::Duplicate);
+                }
+                faults
+            }
+        }
+    }
+}
+
+fn happens(rng: &mut StdRng, probability: f64) -> bool {
+    rng.gen_bool(probability.clamp(0.0, 1.0))
+}
+
+/// Bytes delivered by one `poll_read`
+struct Chunk {
+    delay: Option<Duration>,
+    data: Vec<u8>,
+}
+
+/// Byte stream wrapper corrupting frames read from `IO`, see [module docs](self)
+pub struct FaultInjector<IO> {
+    inner: IO,
+    source: FaultSource,
+    max_frame_length: usize,
+
+    /// Read from `inner`, not yet split into frames
+    rx: BytesMut,
+    /// Ready to be delivered
+    out: VecDeque<Chunk>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    eof: bool,
+
+    stats: FaultStats,
+}
+
+impl<IO> FaultInjector<IO> {
+    /// Apply `script[n]` to the n-th response frame, frames after the end of the script pass clean
+    pub fn scripted(io: IO, script: impl IntoIterator<Item = Vec<FrameFault>>) -> Self {
+        Self::new(io, FaultSource::Script(script.into_iter().collect()))
+    }
+
+    /// Generate faults with probabilities from `config`, same `seed` gives the same faults
+    pub fn random(io: IO, seed: u64, config: FaultConfig) -> Self {
+        Self::new(
+            io,
+            FaultSource::Random {
+                rng: Box::new(StdRng::seed_from_u64(seed)),
+                config,
+            },
+        )
+    }
+
+    fn new(inner: IO, source: FaultSource) -> Self {
+        Self {
+            inner,
+            source,
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            rx: BytesMut::new(),
+            out: VecDeque::new(),
+            sleep: None,
+            eof: false,
+            stats: FaultStats::default(),
+        }
+    }
+
+    /// Longer frames are passed through unchanged, should match the codec limit
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    /// Release the wrapped stream, bytes read from it but not yet delivered are lost
+    pub fn into_inner(self) -> IO {
+        self.inner
+    }
+
+    fn pass(&mut self, len: usize) {
+        self.out.push_back(Chunk {
+            delay: None,
+            data: self.rx.split_to(len).to_vec(),
+        });
+    }
+
+    /// Move as much of `rx` as possible to `out`, `false` if more bytes are needed
+    fn process(&mut self) -> bool {
+        let magick = Info::Magick as u8;
+        match self.rx.iter().position(|b| *b == magick) {
+            Some(0) => {}
+            Some(pos) => {
+                self.pass(pos);
+                return true;
+            }
+            None if self.rx.is_empty() => return false,
+            None => {
+                self.pass(self.rx.len());
+                return true;
+            }
+        }
+
+        let frame_len = match parse_header(&self.rx) {
+            FrameHeader::Incomplete => return false,
+            FrameHeader::Valid {
+                header_len,
+                body_len,
+            } if body_len <= self.max_frame_length as u64 => header_len + body_len as usize,
+            _ => {
+                // not a frame, let the codec deal with it
+                self.pass(1);
+                return true;
+            }
+        };
+        if self.rx.len() < frame_len {
+            return false;
+        }
+
+        let frame = self.rx.split_to(frame_len).to_vec();
+        let faults = self.source.next(frame.len());
+        self.inject(frame, faults);
+        true
+    }
+
+    fn inject(&mut self, mut frame: Vec<u8>, faults: Vec<FrameFault>) {
+        self.stats.frames += 1;
+
+        let mut delay = None;
+        let mut splits = vec![];
+        let mut garbage = vec![];
+        let mut copies = 1;
+        for fault in faults {
+            match fault {
+                FrameFault::Delay(d) => {
+                    self.stats.delayed_frames += 1;
+                    delay = Some(d);
+                }
+                FrameFault::DropByte(offset) if offset < frame.len() => {
+                    self.stats.dropped_bytes += 1;
+                    frame.remove(offset);
+                }
+                FrameFault::FlipBit { offset, bit } if offset < frame.len() => {
+                    self.stats.flipped_bits += 1;
+                    frame[offset] ^= 1 << (bit % 8);
+                }
+                FrameFault::Split(offset) if offset > 0 && offset < frame.len() => {
+                    self.stats.split_frames += 1;
+                    splits.push(offset);
+                }
+                FrameFault::Garbage(bytes) => {
+                    self.stats.garbage_bytes += bytes.len() as u64;
+                    garbage.extend(bytes);
+                }
+                FrameFault::Duplicate => {
+                    self.stats.duplicated_frames += 1;
+                    copies = 2;
+                }
+                FrameFault::Drop => {
+                    self.stats.dropped_frames += 1;
+                    return;
+                }
+                // offset beyond the frame (shortened by an earlier DropByte)
+                _ => {}
+            }
+        }
+
+        splits.retain(|s| *s < frame.len());
+        splits.sort_unstable();
+        splits.dedup();
+
+        if !garbage.is_empty() {
+            self.out.push_back(Chunk {
+                delay: delay.take(),
+                data: garbage,
+            });
+        }
+        for _ in 0..copies {
+            let mut start = 0;
+            for end in splits.iter().copied().chain([frame.len()]) {
+                self.out.push_back(Chunk {
+                    delay: delay.take(),
+                    data: frame[start..end].to_vec(),
+                });
+                start = end;
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for FaultInjector<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(chunk) = this.out.front_mut() {
+                if let Some(delay) = chunk.delay.take() {
+                    this.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+                }
+                if let Some(sleep) = this.sleep.as_mut() {
+                    ready!(sleep.as_mut().poll(cx));
+                    this.sleep = None;
+                }
+
+                let len = chunk.data.len().min(buf.remaining());
+                buf.put_slice(&chunk.data[..len]);
+                chunk.data.drain(..len);
+                if chunk.data.is_empty() {
+                    this.out.pop_front();
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.process() {
+                continue;
+            }
+            if this.eof {
+                // incomplete frame at the end of stream
+                if !this.rx.is_empty() {
+                    this.pass(this.rx.len());
+                    continue;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut data = [0u8; READ_CHUNK_SIZE];
+            let mut read_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.rx.extend_from_slice(read_buf.filled());
+            }
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for FaultInjector<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::{mock::MockDevice, Error, LaserSetup};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn connect(
+        device: &MockDevice,
+        wrap: impl FnOnce(DuplexStream) -> FaultInjector<DuplexStream>,
+    ) -> LaserSetup<FaultInjector<DuplexStream>> {
+        let (client, io) = tokio::io::duplex(64 * 1024);
+        device.serve(io);
+        LaserSetup::from_io(wrap(client), TIMEOUT)
+    }
+
+    fn scripted(faults: Vec<FrameFault>) -> LaserSetup<FaultInjector<DuplexStream>> {
+        connect(&MockDevice::new(), |io| {
+            FaultInjector::scripted(io, vec![faults])
+        })
+    }
+
+    fn is_timeout(res: Result<crate::CurrentControlState, Error>) -> bool {
+        matches!(res, Err(e) if matches!(e.root(), Error::Timeout { .. }))
+    }
+
+    #[tokio::test]
+    async fn garbage() {
+        // magick followed by overlong length prefix
+        let mut garbage = vec![0x00, Info::Magick as u8];
+        garbage.extend([0xFF; 10]);
+        let mut laser = scripted(vec![FrameFault::Garbage(garbage)]);
+
+        laser.read().await.unwrap();
+        assert_eq!(laser.corrupted_frames(), 1);
+        assert_eq!(laser.stale_frames(), 0);
+        assert_eq!(laser.into_inner().stats().garbage_bytes, 12);
+    }
+
+    #[tokio::test]
+    async fn stray_magick() {
+        // looks like a header of a 127-byte frame, the answer waits behind it
+        let garbage = vec![Info::Magick as u8, 0x7F];
+        let mut laser = scripted(vec![FrameFault::Garbage(garbage.clone())]);
+
+        laser.read().await.unwrap();
+        laser.read().await.unwrap();
+        assert_eq!(laser.corrupted_frames(), 1);
+        assert_eq!(laser.stale_frames(), 0);
+
+        let laser = scripted(vec![FrameFault::Garbage(garbage)]);
+        let (handle, _) = laser.spawn();
+        handle.read().await.unwrap();
+        handle.read().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn split() {
+        let mut laser = scripted(vec![FrameFault::Split(3)]);
+
+        laser.read().await.unwrap();
+        laser.read().await.unwrap();
+        assert_eq!(laser.corrupted_frames(), 0);
+        assert_eq!(laser.into_inner().stats().split_frames, 1);
+    }
+
+    #[tokio::test]
+    async fn flipped_length() {
+        // body one byte longer or shorter than sent, can't be decoded
+        let mut laser = scripted(vec![FrameFault::FlipBit { offset: 1, bit: 0 }]);
+
+        assert!(is_timeout(laser.read().await));
+        laser.read().await.unwrap();
+        assert!(laser.corrupted_frames() >= 1);
+        assert_eq!(laser.into_inner().stats().flipped_bits, 1);
+    }
+
+    #[tokio::test]
+    async fn duplicate() {
+        let mut laser = scripted(vec![FrameFault::Duplicate]);
+
+        laser.read().await.unwrap();
+        laser.read().await.unwrap();
+        assert_eq!(laser.stale_frames(), 1);
+        assert_eq!(laser.corrupted_frames(), 0);
+        assert_eq!(laser.into_inner().stats().duplicated_frames, 1);
+    }
+
+    #[tokio::test]
+    async fn drop() {
+        let mut laser = scripted(vec![FrameFault::Drop]);
+
+        assert!(is_timeout(laser.read().await));
+        laser.read().await.unwrap();
+        assert_eq!(laser.stale_frames(), 0);
+        assert_eq!(laser.into_inner().stats().dropped_frames, 1);
+    }
+
+    #[tokio::test]
+    async fn seeded() {
+        const READS: usize = 100;
+
+        let config = FaultConfig {
+            drop_frame: 0.05,
+            split: 0.2,
+            garbage: 0.1,
+            flip_bit: 0.05,
+            duplicate: 0.05,
+            ..Default::default()
+        };
+        let mut laser = connect(&MockDevice::new(), |io| {
+            FaultInjector::random(io, 1, config)
+        });
+
+        let mut ok = 0;
+        for _ in 0..READS {
+            if laser.read().await.is_ok() {
+                ok += 1;
+            }
+        }
+        // flipped bits may still decode to a wrong but valid answer, no checksum
+        assert!(
+            ok >= READS * 9 / 10,
+            "only {} of {} reads succeeded",
+            ok,
+            READS
+        );
+        laser.read().await.unwrap();
+
+        let (stale, corrupted) = (laser.stale_frames(), laser.corrupted_frames());
+        let stats = laser.into_inner().stats();
+        assert!(stats.duplicated_frames > 0 && stats.flipped_bits > 0);
+        assert!(stale >= stats.duplicated_frames);
+        assert!(corrupted > 0);
+    }
+}
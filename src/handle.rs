@@ -0,0 +1,543 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use embedded_hal_async::i2c::Operation;
+use futures::future::select_all;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::protobuf::messages::{Request, Response};
+use crate::{
+    control_result, enumerate_request, enumerate_result, i2c, read_request, write_request,
+    ControlState, CurrentControlState, DeviceInfo, Error, I2CBus, I2cOperationResult, LaserSetup,
+};
+
+/// Which of [`LaserSetup`] timeouts applies to a request
+#[derive(Clone, Copy)]
+enum TimeoutKind {
+    Control,
+    I2c,
+}
+
+struct Job {
+    request: Request,
+    timeout: TimeoutKind,
+    reply: oneshot::Sender<Result<Response, Error>>,
+}
+
+/// Request sent and waiting for the answer
+struct InFlight {
+    id: u32,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<Response, Error>>,
+}
+
+/// What woke up the serving task while requests are in flight
+enum Event {
+    Job(Option<(u64, Job)>),
+    Response(Option<Result<Response, Error>>),
+    Timeout,
+    Cancelled,
+}
+
+fn enqueue(queues: &mut VecDeque<(u64, VecDeque<Job>)>, (client, job): (u64, Job)) {
+    match queues.iter_mut().find(|(c, _)| *c == client) {
+        Some((_, queue)) => queue.push_back(job),
+        None => queues.push_back((client, VecDeque::from([job]))),
+    }
+}
+
+/// Cloneable handle to [`LaserSetup`] running in a background task
+///
+/// Requests of all clones are queued and served round-robin: a clone with many queued
+/// requests does not delay others by more than one request each. Up to
+/// [`LaserSetup::set_pipeline_window`] requests are sent without waiting for answers,
+/// which are matched back by id. Dropping a request future cancels it, the answer is
+/// discarded if it was already sent. The task stops when all clones are dropped.
+pub struct LaserSetupHandle {
+    client: u64,
+    next_client: Arc<AtomicU64>,
+    jobs: mpsc::UnboundedSender<(u64, Job)>,
+    /// Buses known to all clones, for validation
+    i2c_buses: Arc<Mutex<Option<Vec<I2CBus>>>>,
+    /// [`LaserSetup::max_i2c_payload`] of all clones
+    max_i2c_payload: Arc<Mutex<Option<usize>>>,
+}
+
+impl Clone for LaserSetupHandle {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.next_client.fetch_add(1, Ordering::Relaxed),
+            next_client: self.next_client.clone(),
+            jobs: self.jobs.clone(),
+            i2c_buses: self.i2c_buses.clone(),
+            max_i2c_payload: self.max_i2c_payload.clone(),
+        }
+    }
+}
+
+/// Take job of the next client in round-robin order
+fn next_job(queues: &mut VecDeque<(u64, VecDeque<Job>)>) -> Option<Job> {
+    let (client, mut queue) = queues.pop_front()?;
+    let job = queue.pop_front();
+    if !queue.is_empty() {
+        queues.push_back((client, queue));
+    }
+    job
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin + Send + 'static> LaserSetup<IO> {
+    /// Move connection to a background task, [`LaserSetup`] is given back by the returned
+    /// `JoinHandle` when all handles are dropped
+    pub fn spawn(self) -> (LaserSetupHandle, JoinHandle<LaserSetup<IO>>) {
+        let (jobs, rx) = mpsc::unbounded_channel();
+        let handle = LaserSetupHandle {
+            client: 0,
+            next_client: Arc::new(AtomicU64::new(1)),
+            jobs,
+            i2c_buses: Arc::new(Mutex::new(self.i2c_buses.clone())),
+            max_i2c_payload: Arc::new(Mutex::new(self.max_i2c_payload)),
+        };
+        (handle, tokio::spawn(self.serve_jobs(rx)))
+    }
+
+    async fn serve_jobs(mut self, mut rx: mpsc::UnboundedReceiver<(u64, Job)>) -> Self {
+        // clients having queued jobs, in round-robin order
+        let mut queues = VecDeque::new();
+        // in order of sending
+        let mut in_flight: Vec<InFlight> = vec![];
+        let mut open = true;
+
+        loop {
+            while let Ok(job) = rx.try_recv() {
+                enqueue(&mut queues, job);
+            }
+
+            // answers to cancelled requests will be discarded as stale
+            in_flight.retain(|f| {
+                if f.reply.is_closed() {
+                    log::trace!("Request id={} cancelled while in flight", f.id);
+                }
+                !f.reply.is_closed()
+            });
+
+            if in_flight.len() < self.pipeline_window {
+                if let Some(job) = next_job(&mut queues) {
+                    if job.reply.is_closed() {
+                        log::trace!("Request id={} cancelled before sending", job.request.id);
+                        continue;
+                    }
+
+                    let id = job.request.id;
+                    let timeout = match job.timeout {
+                        TimeoutKind::Control => self.control_timeout,
+                        TimeoutKind::I2c => self.i2c_timeout,
+                    };
+                    match self.send_request(job.request).await {
+                        Ok(()) => in_flight.push(InFlight {
+                            id,
+                            deadline: Instant::now() + timeout,
+                            reply: job.reply,
+                        }),
+                        Err(e) => {
+                            let _ = job.reply.send(Err(e));
+                        }
+                    }
+                    continue;
+                }
+            }
+
+            if in_flight.is_empty() {
+                match rx.recv().await {
+                    Some(job) => enqueue(&mut queues, job),
+                    None => return self,
+                }
+                continue;
+            }
+
+            let deadline = in_flight.iter().map(|f| f.deadline).min();
+            let event = tokio::select! {
+                job = rx.recv(), if open => Event::Job(job),
+                resp = self.io.next() => Event::Response(resp),
+                _ = tokio::time::sleep_until(deadline.expect("in_flight is not empty")) => {
+                    Event::Timeout
+                }
+                _ = select_all(in_flight.iter_mut().map(|f| Box::pin(f.reply.closed()))) => {
+                    Event::Cancelled
+                }
+            };
+
+            match event {
+                Event::Job(Some(job)) => enqueue(&mut queues, job),
+                Event::Job(None) => open = false,
+                Event::Response(resp) => self.dispatch(&mut in_flight, resp),
+                Event::Timeout => {
+                    // late answers may wait behind a stray magick taken for a frame header
+                    loop {
+                        match self.decode_stalled() {
+                            Ok(Some(resp)) => self.dispatch(&mut in_flight, Some(Ok(resp))),
+                            Ok(None) => break,
+                            Err(e) => {
+                                self.dispatch(&mut in_flight, Some(Err(e)));
+                                break;
+                            }
+                        }
+                    }
+
+                    let now = Instant::now();
+                    let (expired, rest) = in_flight.drain(..).partition(|f| f.deadline <= now);
+                    in_flight = rest;
+                    for f in expired {
+                        let _ = f.reply.send(Err(Error::Timeout {
+                            request_id: Some(f.id),
+                        }));
+                    }
+                }
+                // removed at the start of the next iteration
+                Event::Cancelled => {}
+            }
+        }
+    }
+
+    /// Route answer to the request with the same id
+    fn dispatch(&mut self, in_flight: &mut Vec<InFlight>, resp: Option<Result<Response, Error>>) {
+        match resp {
+            Some(Ok(resp)) => {
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.response(&resp);
+                }
+                match in_flight.iter().position(|f| f.id == resp.id) {
+                    Some(i) => {
+                        log::trace!("Received response id={}", resp.id);
+                        let f = in_flight.remove(i);
+                        let _ = f.reply.send(Self::check_device(&resp).map(|_| resp));
+                    }
+                    None => {
+                        self.stale_frames += 1;
+                        log::warn!("Discarding response id={} to no pending request", resp.id);
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                let mut e = Some(e);
+                for f in in_flight.drain(..) {
+                    let _ = f
+                        .reply
+                        .send(Err(e.take().unwrap_or(Error::UnexpectedEndOfStream)));
+                }
+            }
+            None => {
+                for f in in_flight.drain(..) {
+                    let _ = f.reply.send(Err(Error::UnexpectedEndOfStream));
+                }
+            }
+        }
+    }
+}
+
+impl LaserSetupHandle {
+    async fn exchange(&self, request: Request, timeout: TimeoutKind) -> Result<Response, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.jobs
+            .send((
+                self.client,
+                Job {
+                    request,
+                    timeout,
+                    reply,
+                },
+            ))
+            .map_err(|_| Error::UnexpectedEndOfStream)?;
+        rx.await.map_err(|_| Error::UnexpectedEndOfStream)?
+    }
+
+    async fn control_exchange(
+        &self,
+        req: Request,
+        operation: &'static str,
+    ) -> Result<CurrentControlState, Error> {
+        let req_id = req.id;
+        async { control_result(self.exchange(req, TimeoutKind::Control).await?) }
+            .await
+            .map_err(|e: Error| e.context(operation, req_id))
+    }
+
+    /// See [`LaserSetup::handshake`]
+    pub async fn handshake(&self) -> Result<DeviceInfo, Error> {
+        let req = read_request();
+        let req_id = req.id;
+        self.exchange(req, TimeoutKind::Control)
+            .await
+            .map(|resp| DeviceInfo {
+                device_id: resp.device_id,
+                protocol_version: resp.protocol_version,
+            })
+            .map_err(|e| e.context("handshake", req_id))
+    }
+
+    pub async fn write(&self, request: &impl ControlState) -> Result<CurrentControlState, Error> {
+        self.control_exchange(write_request(request), "write control state")
+            .await
+    }
+
+    pub async fn read(&self) -> Result<CurrentControlState, Error> {
+        self.control_exchange(read_request(), "read control state")
+            .await
+    }
+
+    pub async fn enumerate_i2c_buses(&self) -> Result<Vec<I2CBus>, Error> {
+        let req = enumerate_request();
+        let req_id = req.id;
+        async {
+            let buses = enumerate_result(self.exchange(req, TimeoutKind::I2c).await?)?;
+            *self.i2c_buses.lock().unwrap() = Some(buses.clone());
+            Ok(buses)
+        }
+        .await
+        .map_err(|e: Error| e.context("enumerate I2C buses", req_id))
+    }
+
+    /// See [`LaserSetup::i2c_buses`]
+    pub async fn i2c_buses(&self) -> Result<Vec<I2CBus>, Error> {
+        let cached = self.i2c_buses.lock().unwrap().clone();
+        match cached {
+            Some(buses) => Ok(buses),
+            None => self.enumerate_i2c_buses().await,
+        }
+    }
+
+    /// See [`LaserSetup::i2c_bus_info`]
+    pub fn i2c_bus_info(&self, bus_id: u32) -> Option<I2CBus> {
+        self.i2c_buses
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .iter()
+            .find(|b| b.id == bus_id)
+            .copied()
+    }
+
+    /// See [`LaserSetup::set_max_i2c_payload`], applies to all clones
+    pub fn set_max_i2c_payload(&self, max: Option<usize>) {
+        *self.max_i2c_payload.lock().unwrap() = max;
+    }
+
+    pub fn max_i2c_payload(&self) -> Option<usize> {
+        *self.max_i2c_payload.lock().unwrap()
+    }
+
+    /// [`LaserSetup::transaction_detailed`] on `bus`
+    pub async fn transaction_detailed_on(
+        &self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<Vec<I2cOperationResult>, Error> {
+        if self.i2c_buses.lock().unwrap().is_none() {
+            self.enumerate_i2c_buses().await?;
+        }
+        i2c::check_bus(self.i2c_buses.lock().unwrap().as_deref(), bus)?;
+        i2c::check_payload(self.max_i2c_payload(), operations)?;
+
+        let req = i2c::sequence_request(bus, address, operations);
+        let req_id = req.id;
+        async {
+            let resp = self.exchange(req, TimeoutKind::I2c).await?;
+            i2c::sequence_result(bus, address, operations, resp)
+        }
+        .await
+        .map_err(|e: Error| e.context("I2C transaction", req_id))
+    }
+
+    /// [`I2c::transaction`](crate::I2c::transaction) on `bus`
+    pub async fn transaction_on(
+        &self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        let results = self
+            .transaction_detailed_on(bus, address, operations)
+            .await?;
+        i2c::results_to_error(operations, &results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::mock::{MockDevice, ReceivedRequest, Reply, RequestKind};
+    use crate::{CameraState, ValveState};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Switch to channel only
+    struct Channel(u32);
+
+    impl ControlState for Channel {
+        fn valve(&self) -> Option<ValveState> {
+            None
+        }
+
+        fn channel(&self) -> Option<u32> {
+            Some(self.0)
+        }
+
+        fn camera(&self) -> Option<CameraState> {
+            None
+        }
+    }
+
+    fn spawn(
+        device: &MockDevice,
+        window: usize,
+    ) -> (
+        LaserSetupHandle,
+        JoinHandle<LaserSetup<tokio::io::DuplexStream>>,
+    ) {
+        let mut laser = device.connect(TIMEOUT);
+        laser.set_pipeline_window(window);
+        laser.spawn()
+    }
+
+    fn is_timeout<T>(res: &Result<T, Error>) -> bool {
+        matches!(res, Err(e) if matches!(e.root(), Error::Timeout { .. }))
+    }
+
+    #[tokio::test]
+    async fn pipelined_answers_by_id() {
+        let device = MockDevice::new();
+        let (handle, _) = spawn(&device, 3);
+
+        // first request is never answered, the others are matched past it
+        device.script(Reply::NoAnswer);
+        let started = Instant::now();
+        let res = join_all((1..=3).map(|ch| {
+            let handle = handle.clone();
+            async move { (handle.write(&Channel(ch)).await, started.elapsed()) }
+        }))
+        .await;
+
+        assert!(is_timeout(&res[0].0));
+        for (ch, (state, elapsed)) in (2..=3).zip(&res[1..]) {
+            assert_eq!(state.as_ref().unwrap().channel, ch);
+            assert!(*elapsed < TIMEOUT);
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_leaves_others_in_flight() {
+        let device = MockDevice::new();
+        let (handle, task) = spawn(&device, 3);
+
+        device.script(Reply::Normal);
+        device.script(Reply::NoAnswer);
+        let res = join_all((0..3).map(|_| handle.read())).await;
+
+        assert!(res[0].is_ok() && res[2].is_ok());
+        assert!(is_timeout(&res[1]));
+
+        drop(handle);
+        assert_eq!(task.await.unwrap().stale_frames(), 0);
+    }
+
+    #[tokio::test]
+    async fn late_answer_to_dropped_request_is_stale() {
+        let device = MockDevice::new();
+        let (handle, task) = spawn(&device, 1);
+
+        device.script(Reply::Delay(Duration::from_millis(30)));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), handle.read())
+                .await
+                .is_err()
+        );
+        handle.read().await.unwrap();
+
+        drop(handle);
+        let laser = task.await.unwrap();
+        assert_eq!(laser.stale_frames(), 1);
+    }
+
+    #[tokio::test]
+    async fn queue_resumes_after_full_window() {
+        let device = MockDevice::new();
+        let (handle, _) = spawn(&device, 2);
+
+        device.script(Reply::Delay(Duration::from_millis(20)));
+        let res = join_all((0..6).map(|_| handle.read())).await;
+
+        assert!(res.iter().all(Result::is_ok));
+        assert_eq!(device.received().len(), 6);
+    }
+
+    fn is_channel(request: &ReceivedRequest, ch: u32) -> bool {
+        matches!(request.kind, RequestKind::Control { channel: Some(c), .. } if c == ch)
+    }
+
+    #[tokio::test]
+    async fn round_robin_between_clones() {
+        let device = MockDevice::new();
+        let (busy, _) = spawn(&device, 1);
+        let other = busy.clone();
+
+        // everything is queued while the first answer is delayed
+        device.script(Reply::Delay(Duration::from_millis(20)));
+        let (reads, write) = tokio::join!(
+            join_all((0..10).map(|_| busy.read())),
+            other.write(&Channel(7))
+        );
+        assert!(reads.iter().all(Result::is_ok));
+        write.unwrap();
+
+        let position = device
+            .received()
+            .iter()
+            .position(|r| is_channel(r, 7))
+            .unwrap();
+        // after the one in flight and at most one more of the busy clone
+        assert!(position <= 2, "served as request {}", position);
+    }
+
+    #[tokio::test]
+    async fn dropped_before_sending_is_not_sent() {
+        let device = MockDevice::new();
+        let (handle, _) = spawn(&device, 1);
+
+        device.script(Reply::Delay(Duration::from_millis(30)));
+        let (first, dropped) = tokio::join!(
+            handle.read(),
+            tokio::time::timeout(Duration::from_millis(10), handle.write(&Channel(7)))
+        );
+        first.unwrap();
+        assert!(dropped.is_err());
+        handle.read().await.unwrap();
+
+        assert_eq!(device.received().len(), 2);
+        assert!(!device.received().iter().any(|r| is_channel(r, 7)));
+    }
+
+    #[tokio::test]
+    async fn dropped_in_flight_does_not_fail_next() {
+        let device = MockDevice::new();
+        let (handle, _) = spawn(&device, 1);
+
+        device.script(Reply::Delay(Duration::from_millis(30)));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), handle.write(&Channel(3)))
+                .await
+                .is_err()
+        );
+
+        // late answer to channel 3 must not be taken for this one
+        assert_eq!(handle.write(&Channel(5)).await.unwrap().channel, 5);
+    }
+}
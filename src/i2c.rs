@@ -0,0 +1,422 @@
+use std::time::Duration;
+
+use embedded_hal_async::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+
+use crate::protobuf::{
+    self,
+    messages::{
+        i2c_operation::Operation as I2cOperationType, i2c_response::Response, i2c_result,
+        I2cOperation, I2cReadRequest, I2cReadResponse, I2cRequest, I2cResponse, I2cResult,
+        I2cSequence, I2cSequenceResult, I2cWriteRequest, Status,
+    },
+};
+use crate::{Error, I2CBus, I2c, LaserSetup, LaserSetupHandle};
+
+pub use crate::protobuf::messages::I2cResultCode;
+
+/// Outcome of one operation of I2C transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct I2cOperationResult {
+    /// `None` if device skipped operation because an earlier one failed
+    pub code: Option<I2cResultCode>,
+    /// Bytes actually read, shorter than requested if read failed
+    pub data: Vec<u8>,
+}
+
+impl I2cOperationResult {
+    pub fn is_ok(&self) -> bool {
+        matches!(self.code, Some(code) if error_kind(code).is_none())
+    }
+}
+
+fn error_kind(code: I2cResultCode) -> Option<ErrorKind> {
+    match code {
+        I2cResultCode::I2cInvalidBus => Some(ErrorKind::Bus),
+        I2cResultCode::I2cTooLongData => Some(ErrorKind::Overrun),
+        I2cResultCode::I2cNak => Some(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown)),
+        _ => None,
+    }
+}
+
+/// Firmware reports NAK per operation only, without the byte. Slave can't NAK bytes it
+/// sends to master, so NAK on a read is address-phase. A write may be refused at the
+/// address or at any data byte, that is unknown.
+fn nak_source(operation: &Operation<'_>) -> NoAcknowledgeSource {
+    match operation {
+        Operation::Read(_) => NoAcknowledgeSource::Address,
+        Operation::Write(_) => NoAcknowledgeSource::Unknown,
+    }
+}
+
+/// Convert per-operation results into embedded-hal error of the first failed operation
+pub(crate) fn results_to_error(
+    operations: &[Operation<'_>],
+    results: &[I2cOperationResult],
+) -> Result<(), Error> {
+    for (op, res) in operations.iter().zip(results) {
+        match res.code.and_then(error_kind) {
+            Some(ErrorKind::NoAcknowledge(_)) => {
+                return Err(Error::I2C(ErrorKind::NoAcknowledge(nak_source(op))))
+            }
+            Some(kind) => return Err(Error::I2C(kind)),
+            None => {}
+        }
+    }
+
+    if results.iter().all(|r| r.code.is_some()) {
+        Ok(())
+    } else {
+        // operations skipped, but the device did not say why
+        Err(Error::I2C(ErrorKind::Bus))
+    }
+}
+
+/// Fail with [`Error::InvalidBus`] if `bus` is not in known `buses`
+pub(crate) fn check_bus(buses: Option<&[I2CBus]>, bus: u32) -> Result<(), Error> {
+    match buses {
+        Some(buses) if !buses.iter().any(|b| b.id == bus) => Err(Error::InvalidBus {
+            bus,
+            available: buses.iter().map(|b| b.id).collect(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Fail with [`Error::I2cPayloadTooLong`] if any of `operations` is longer than `max`
+pub(crate) fn check_payload(max: Option<usize>, operations: &[Operation<'_>]) -> Result<(), Error> {
+    let max = match max {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    for (index, op) in operations.iter().enumerate() {
+        let length = match op {
+            Operation::Write(w) => w.len(),
+            Operation::Read(r) => r.len(),
+        };
+        if length > max {
+            return Err(Error::I2cPayloadTooLong { index, length, max });
+        }
+    }
+    Ok(())
+}
+
+/// Request performing `operations` with device `address` on `bus`
+pub(crate) fn sequence_request(
+    bus: u32,
+    address: u8,
+    operations: &[Operation<'_>],
+) -> protobuf::messages::Request {
+    let mut req = protobuf::new_request();
+
+    req.i2c = Some(I2cRequest {
+        request: Some(protobuf::messages::i2c_request::Request::Sequence(
+            I2cSequence {
+                bus,
+                address: address as u32,
+                operations: operations
+                    .iter()
+                    .map(|o| I2cOperation {
+                        operation: Some(match o {
+                            Operation::Write(w) => I2cOperationType::Write(I2cWriteRequest {
+                                address: address as u32,
+                                data: w.to_vec(),
+                            }),
+                            Operation::Read(r) => I2cOperationType::Read(I2cReadRequest {
+                                address: address as u32,
+                                length: r.len() as u32,
+                            }),
+                        }),
+                    })
+                    .collect(),
+            },
+        )),
+    });
+    req
+}
+
+/// Check response to [`sequence_request`] and copy read data into `operations`
+pub(crate) fn sequence_result(
+    bus: u32,
+    address: u8,
+    operations: &mut [Operation<'_>],
+    resp: protobuf::messages::Response,
+) -> Result<Vec<I2cOperationResult>, Error> {
+    let status = crate::global_status(&resp)?;
+    let sequence = match (status, resp.i2c) {
+        (
+            Status::Ok | Status::I2c,
+            Some(I2cResponse {
+                response: Some(Response::Sequence(s)),
+            }),
+        ) => s,
+        (Status::Ok, _) => {
+            return Err(Error::UnexpectedResponse {
+                expected: "I2C sequence result",
+            })
+        }
+        (Status::I2c, _) => return Err(Error::I2C(ErrorKind::Bus)),
+        (e, _) => return Err(Error::Protocol(e)),
+    };
+
+    parse_sequence_result(bus, address, operations, sequence, status == Status::Ok)
+}
+
+/// Validate `sequence` against requested `operations` and copy read data
+///
+/// If the device reported an I2C error (`complete == false`) it may return fewer results
+/// and shorter reads than requested.
+fn parse_sequence_result(
+    expected_bus: u32,
+    address: u8,
+    operations: &mut [Operation<'_>],
+    sequence: I2cSequenceResult,
+    complete: bool,
+) -> Result<Vec<I2cOperationResult>, Error> {
+    let I2cSequenceResult {
+        operations: res_operations,
+        bus,
+        address: res_address,
+    } = sequence;
+
+    if res_operations.len() > operations.len()
+        || (complete && res_operations.len() != operations.len())
+    {
+        return Err(Error::I2cLengthMismatch {
+            expected: operations.len(),
+            actual: res_operations.len(),
+        });
+    }
+    if bus != expected_bus {
+        return Err(Error::BusMismatch {
+            expected: expected_bus,
+            actual: bus,
+        });
+    }
+    if res_address != address as u32 {
+        return Err(Error::AddressMismatch {
+            expected: address as u32,
+            actual: res_address,
+        });
+    }
+
+    let mut results = Vec::with_capacity(operations.len());
+    for (index, (op, res)) in operations.iter_mut().zip(res_operations).enumerate() {
+        let status2code =
+            |status| protobuf::enum_value(status, I2cResultCode::from_i32, "I2cResultCode");
+
+        match (op, res) {
+            (
+                Operation::Read(buf),
+                I2cResult {
+                    operation: Some(i2c_result::Operation::Read(I2cReadResponse { data, status })),
+                },
+            ) => {
+                let code = status2code(status)?;
+                let ok = error_kind(code).is_none();
+                if (ok && data.len() != buf.len()) || data.len() > buf.len() {
+                    return Err(Error::ReadLengthMismatch {
+                        index,
+                        expected: buf.len(),
+                        actual: data.len(),
+                    });
+                }
+                buf[..data.len()].copy_from_slice(&data);
+                results.push(I2cOperationResult {
+                    code: Some(code),
+                    data,
+                });
+            }
+            (
+                Operation::Write(_),
+                I2cResult {
+                    operation: Some(i2c_result::Operation::Write(status)),
+                },
+            ) => results.push(I2cOperationResult {
+                code: Some(status2code(status)?),
+                data: vec![],
+            }),
+            _ => return Err(Error::I2cOperationMismatch { index }),
+        }
+    }
+
+    results.resize(
+        operations.len(),
+        I2cOperationResult {
+            code: None,
+            data: vec![],
+        },
+    );
+
+    Ok(results)
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> LaserSetup<IO> {
+    /// Perform I2C transaction on the selected bus and report result of every operation
+    ///
+    /// Unlike [`I2c::transaction`] a failed operation is not an error: data read before
+    /// the failure is copied into read buffers and returned together with result codes.
+    pub async fn transaction_detailed(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<Vec<I2cOperationResult>, Error> {
+        self.transaction_detailed_on(self.selected_i2c_bus, address, operations)
+            .await
+    }
+
+    /// [`LaserSetup::transaction_detailed`] on `bus`, selected bus is not changed
+    ///
+    /// Buses are enumerated before the first transaction, unknown `bus` fails with
+    /// [`Error::InvalidBus`] without an I2C request.
+    pub async fn transaction_detailed_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<Vec<I2cOperationResult>, Error> {
+        self.sequence_exchange(bus, address, operations, self.i2c_timeout)
+            .await
+    }
+
+    /// [`LaserSetup::transaction_detailed_on`] waiting for the answer for `timeout`
+    pub(crate) async fn sequence_exchange(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+        timeout: Duration,
+    ) -> Result<Vec<I2cOperationResult>, Error> {
+        if self.i2c_buses.is_none() {
+            self.enumerate_i2c_buses().await?;
+        }
+        check_bus(self.i2c_buses.as_deref(), bus)?;
+        check_payload(self.max_i2c_payload, operations)?;
+
+        let req = sequence_request(bus, address, operations);
+        let req_id = req.id;
+
+        async {
+            let resp = self.exchange(req, timeout).await?;
+            sequence_result(bus, address, operations, resp)
+        }
+        .await
+        .map_err(|e: Error| e.context("I2C transaction", req_id))
+    }
+
+    /// [`I2c::transaction`] on `bus`, selected bus is not changed
+    pub async fn transaction_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        let results = self
+            .transaction_detailed_on(bus, address, operations)
+            .await?;
+        results_to_error(operations, &results)
+    }
+}
+
+impl<IO> LaserSetup<IO> {
+    /// I2C device for `bus` sharing connection with others through `laser`
+    ///
+    /// Every transaction locks the mutex, so drivers on different buses can coexist:
+    ///
+    /// ```no_run
+    /// # async fn test(laser: laser_setup_interface::LaserSetup) {
+    /// use laser_setup_interface::LaserSetup;
+    ///
+    /// let laser = tokio::sync::Mutex::new(laser);
+    /// let sensor_bus = LaserSetup::i2c_bus(&laser, 0);
+    /// let eeprom_bus = LaserSetup::i2c_bus(&laser, 1);
+    /// # }
+    /// ```
+    ///
+    /// Use [`LaserSetupHandle::i2c_bus`] to share between tasks.
+    pub fn i2c_bus(laser: &Mutex<Self>, bus: u32) -> SharedI2cBus<'_, IO> {
+        SharedI2cBus { laser, bus }
+    }
+}
+
+impl<IO> embedded_hal_async::i2c::ErrorType for LaserSetup<IO> {
+    type Error = Error;
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> I2c for LaserSetup<IO> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let results = self.transaction_detailed(address, operations).await?;
+        results_to_error(operations, &results)
+    }
+}
+
+/// I2C bus of [`LaserSetup`] shared through a mutex, see [`LaserSetup::i2c_bus`]
+pub struct SharedI2cBus<'a, IO> {
+    laser: &'a Mutex<LaserSetup<IO>>,
+    bus: u32,
+}
+
+impl<IO> Clone for SharedI2cBus<'_, IO> {
+    fn clone(&self) -> Self {
+        Self {
+            laser: self.laser,
+            bus: self.bus,
+        }
+    }
+}
+
+impl<IO> embedded_hal_async::i2c::ErrorType for SharedI2cBus<'_, IO> {
+    type Error = Error;
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> I2c for SharedI2cBus<'_, IO> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.laser
+            .lock()
+            .await
+            .transaction_on(self.bus, address, operations)
+            .await
+    }
+}
+
+/// I2C bus served by [`LaserSetupHandle`], see [`LaserSetupHandle::i2c_bus`]
+#[derive(Clone)]
+pub struct I2cBusHandle {
+    handle: LaserSetupHandle,
+    bus: u32,
+}
+
+impl LaserSetupHandle {
+    /// I2C device for `bus`, can be moved to another task
+    pub fn i2c_bus(&self, bus: u32) -> I2cBusHandle {
+        I2cBusHandle {
+            handle: self.clone(),
+            bus,
+        }
+    }
+}
+
+impl embedded_hal_async::i2c::ErrorType for I2cBusHandle {
+    type Error = Error;
+}
+
+impl I2c for I2cBusHandle {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.handle
+            .transaction_on(self.bus, address, operations)
+            .await
+    }
+}
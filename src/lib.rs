@@ -1,22 +1,46 @@
 #![feature(async_fn_in_trait)]
+#![feature(return_position_impl_trait_in_trait)]
 
 use std::time::Duration;
 
-use embedded_hal_async::i2c::ErrorKind;
 use protobuf::messages::i2c_request;
-use tokio_serial::SerialPortBuilderExt;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::Decoder;
 
 use futures::{SinkExt, StreamExt};
 
+pub mod analyzer;
+mod builder;
+pub mod capture;
+mod chunked;
+mod discovery;
+pub mod fault;
+mod handle;
+mod i2c;
+pub mod mock;
 mod protobuf;
+mod registers;
+mod scan;
 use protobuf::messages::{ControlRequest, Status};
 
 pub use protobuf::messages::ActuatorState as CameraState;
 pub use protobuf::messages::ValveState;
 pub use protobuf::Error;
 
-use protobuf::protobuf_md_codec::ProtobufMDCodec;
+pub use builder::{LaserSetupBuilder, DEFAULT_BAUD_RATE, DEFAULT_TIMEOUT};
+pub use chunked::MemoryLayout;
+pub use discovery::{discover, discover_with, DiscoveredDevice, DiscoveryFilter};
+pub use handle::LaserSetupHandle;
+pub use i2c::{I2cBusHandle, I2cOperationResult, I2cResultCode, SharedI2cBus};
+pub use registers::{ByteOrder, I2cRegisters, RegisterDevice, RegisterWidth};
+pub use scan::{AddressStatus, BusScan, ProbeMethod, ScanOptions};
+pub use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
+
+pub use protobuf::messages;
+pub use protobuf::protobuf_md_codec::{
+    ClientCodec, DeviceCodec, ProtobufMDCodec, DEFAULT_MAX_FRAME_LENGTH,
+};
+pub use protobuf::{new_request, new_response};
 
 pub const CHANNELS_COUNT: u32 = 16;
 
@@ -41,275 +65,389 @@ pub struct CurrentControlState {
     pub camera: CameraState,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Identity of the connected device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device_id: u32,
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct I2CBus {
     pub id: u32,
+    /// Maximal clock frequency, Hz
     pub speed: u32,
 }
 
-pub struct LaserSetup {
-    io: tokio_util::codec::Framed<tokio_serial::SerialStream, ProtobufMDCodec>,
-    timeout: Duration,
+/// Laser-setup client over any byte stream (serial port by default)
+pub struct LaserSetup<IO = tokio_serial::SerialStream> {
+    io: tokio_util::codec::Framed<IO, ProtobufMDCodec>,
+    control_timeout: Duration,
+    i2c_timeout: Duration,
 
     selected_i2c_bus: u32,
+
+    /// id of the last sent request, answer must carry the same id
+    pending_request_id: Option<u32>,
+    /// Responses discarded because of id mismatch
+    stale_frames: u64,
+
+    recorder: Option<capture::Recorder>,
+
+    /// Max requests in flight when served by [`LaserSetupHandle`]
+    pipeline_window: usize,
+
+    /// Result of the last [`LaserSetup::enumerate_i2c_buses`]
+    i2c_buses: Option<Vec<I2CBus>>,
+
+    /// Longest I2C operation the firmware accepts, `None` if unknown
+    max_i2c_payload: Option<usize>,
 }
 
 impl LaserSetup {
+    /// Open serial port `port` at [`DEFAULT_BAUD_RATE`]
+    ///
+    /// Panics if port can't be opened, use [`LaserSetup::builder`] to handle errors
     pub fn new<'a>(port: impl Into<std::borrow::Cow<'a, str>>, timeout: Duration) -> Self {
-        let port = tokio_serial::new(port, 1500000)
-            .open_native_async()
-            .unwrap();
+        Self::builder(port.into()).timeout(timeout).open().unwrap()
+    }
+
+    pub fn builder(port: impl Into<String>) -> LaserSetupBuilder {
+        LaserSetupBuilder::new(port)
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> LaserSetup<IO> {
+    /// Use already opened byte stream: TCP socket (ser2net), unix socket, `tokio::io::duplex`, etc.
+    pub fn from_io(io: IO, timeout: Duration) -> Self {
         Self {
-            io: ProtobufMDCodec.framed(port),
-            timeout,
+            io: ProtobufMDCodec::default().framed(io),
+            control_timeout: timeout,
+            i2c_timeout: timeout,
             selected_i2c_bus: 0,
+            pending_request_id: None,
+            stale_frames: 0,
+            recorder: None,
+            pipeline_window: 1,
+            i2c_buses: None,
+            max_i2c_payload: None,
+        }
+    }
+
+    /// Timeout for valve/camera/channel requests
+    pub fn set_control_timeout(&mut self, timeout: Duration) {
+        self.control_timeout = timeout;
+    }
+
+    /// Timeout for I2C requests
+    pub fn set_i2c_timeout(&mut self, timeout: Duration) {
+        self.i2c_timeout = timeout;
+    }
+
+    /// Frames with longer body are treated as corrupted
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.io.codec_mut().set_max_frame_length(max_frame_length);
+    }
+
+    /// Number of requests [`LaserSetupHandle`] keeps in flight, 1 (default) is strict lock-step
+    ///
+    /// Use more only if the firmware queues requests, otherwise they are lost and time out.
+    pub fn set_pipeline_window(&mut self, window: usize) {
+        self.pipeline_window = window.max(1);
+    }
+
+    /// Release the underlying byte stream
+    pub fn into_inner(self) -> IO {
+        self.io.into_inner()
+    }
+
+    /// Number of responses discarded because they did not answer the last request
+    pub fn stale_frames(&self) -> u64 {
+        self.stale_frames
+    }
+
+    /// Number of received frames dropped because they could not be decoded
+    pub fn corrupted_frames(&self) -> u64 {
+        self.io.codec().corrupted_frames()
+    }
+
+    /// Log all following traffic to `recorder`, `None` stops recording
+    ///
+    /// Returns previous recorder.
+    pub fn set_recorder(
+        &mut self,
+        recorder: Option<capture::Recorder>,
+    ) -> Option<capture::Recorder> {
+        std::mem::replace(&mut self.recorder, recorder)
+    }
+
+    async fn send_request(&mut self, req: protobuf::messages::Request) -> Result<(), Error> {
+        log::trace!("Sending request id={}", req.id);
+        self.pending_request_id = Some(req.id);
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.request(&req);
         }
+        self.io.send(req).await
     }
 
+    /// Read response to the last sent request, answers to older requests are discarded
     pub async fn read_responce(&mut self) -> Result<protobuf::messages::Response, Error> {
-        let res = tokio::time::timeout(self.timeout, self.io.next()).await;
-        match res {
-            Ok(Some(r)) => r,
-            Ok(None) => Err(Error::UnexpectedEndOfStream),
-            Err(_) => Err(Error::Timeout),
+        self.read_responce_timeout(self.control_timeout).await
+    }
+
+    /// Send `req` and wait for the answer
+    async fn exchange(
+        &mut self,
+        req: protobuf::messages::Request,
+        timeout: Duration,
+    ) -> Result<protobuf::messages::Response, Error> {
+        self.send_request(req).await?;
+        self.read_responce_timeout(timeout).await
+    }
+
+    async fn read_responce_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<protobuf::messages::Response, Error> {
+        let resp = self.receive(timeout).await?;
+        Self::check_device(&resp)?;
+        Ok(resp)
+    }
+
+    fn check_device(resp: &protobuf::messages::Response) -> Result<(), Error> {
+        if resp.device_id == protobuf::messages::Info::LaserSetupId as u32
+            && protobuf::is_compatible_protocol(resp.protocol_version)
+        {
+            Ok(())
+        } else {
+            Err(Error::IncompatibleDevice {
+                device_id: resp.device_id,
+                protocol_version: resp.protocol_version,
+            })
         }
     }
 
-    fn decode_current_state(
-        ctrl: &Option<protobuf::messages::ControlResponse>,
-    ) -> CurrentControlState {
-        if let Some(ctrl) = ctrl {
-            CurrentControlState {
-                valve: ValveState::from_i32(ctrl.valve_state).unwrap(),
-                channel: ctrl.selected_channel,
-                camera: CameraState::from_i32(ctrl.actuator_state).unwrap(),
+    /// Drop incomplete frame waiting in the read buffer and decode frames behind it
+    fn decode_stalled(&mut self) -> Result<Option<protobuf::messages::Response>, Error> {
+        let mut buffer = std::mem::take(self.io.read_buffer_mut());
+        let res = self.io.codec_mut().decode_stalled(&mut buffer);
+        *self.io.read_buffer_mut() = buffer;
+        res
+    }
+
+    /// Wait for the answer to the last sent request without checking who answered
+    async fn receive(&mut self, timeout: Duration) -> Result<protobuf::messages::Response, Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let resp = match tokio::time::timeout_at(deadline, self.io.next()).await {
+                Ok(Some(r)) => r?,
+                Ok(None) => return Err(Error::UnexpectedEndOfStream),
+                Err(_) => match self.decode_stalled()? {
+                    Some(r) => r,
+                    None => {
+                        return Err(Error::Timeout {
+                            request_id: self.pending_request_id,
+                        })
+                    }
+                },
+            };
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.response(&resp);
             }
-        } else {
-            panic!("No control field in response")
+
+            match self.pending_request_id {
+                Some(id) if id != resp.id => {
+                    self.stale_frames += 1;
+                    log::warn!(
+                        "Discarding stale response id={} while waiting for id={}",
+                        resp.id,
+                        id
+                    );
+                }
+                _ => {
+                    log::trace!("Received response id={}", resp.id);
+                    self.pending_request_id = None;
+                    return Ok(resp);
+                }
+            }
+        }
+    }
+
+    /// Ask connected device who it is
+    ///
+    /// Fails with [`Error::IncompatibleDevice`] if it is not a Laser-setup or speaks
+    /// an unsupported protocol version. Errors are wrapped in [`Error::Context`], match
+    /// on [`Error::root`]
+    pub async fn handshake(&mut self) -> Result<DeviceInfo, Error> {
+        let req = read_request();
+        let req_id = req.id;
+
+        async {
+            self.send_request(req).await?;
+
+            let resp = self.receive(self.control_timeout).await?;
+            Self::check_device(&resp)?;
+
+            Ok(DeviceInfo {
+                device_id: resp.device_id,
+                protocol_version: resp.protocol_version,
+            })
         }
+        .await
+        .map_err(|e: Error| e.context("handshake", req_id))
     }
 
     pub async fn write(
         &mut self,
         request: &impl ControlState,
     ) -> Result<CurrentControlState, Error> {
-        let mut req = protobuf::new_request();
+        self.control_exchange(write_request(request), "write control state")
+            .await
+    }
 
-        let mut ctrl = protobuf::messages::ControlRequest::default();
+    pub async fn read(&mut self) -> Result<CurrentControlState, Error> {
+        self.control_exchange(read_request(), "read control state")
+            .await
+    }
 
-        if let Some(valve) = request.valve() {
-            ctrl.valve_state = Some(valve as i32);
-        }
-        if let Some(camera) = request.camera() {
-            ctrl.actuator_state = Some(camera as i32);
-        }
-        ctrl.select_channel = request.channel();
+    async fn control_exchange(
+        &mut self,
+        req: protobuf::messages::Request,
+        operation: &'static str,
+    ) -> Result<CurrentControlState, Error> {
+        let req_id = req.id;
 
-        req.control = Some(ctrl);
+        async { control_result(self.exchange(req, self.control_timeout).await?) }
+            .await
+            .map_err(|e: Error| e.context(operation, req_id))
+    }
 
-        self.io.send(req).await?;
+    /// Bus used by [`I2c`] implementation of `LaserSetup` itself, see [`LaserSetup::i2c_bus`]
+    /// and [`LaserSetup::transaction_on`] to work with several buses
+    ///
+    /// Fails with [`Error::InvalidBus`] if the device has no such bus, enumerates buses
+    /// if they are not known yet.
+    pub async fn select_i2c_bus(&mut self, bus_id: u32) -> Result<(), Error> {
+        let buses = self.i2c_buses().await?;
+        i2c::check_bus(Some(&buses), bus_id)?;
+        self.selected_i2c_bus = bus_id;
+        Ok(())
+    }
 
-        let resp = self.read_responce().await?;
-        match Status::from_i32(resp.global_status).unwrap() {
-            Status::Ok => Ok(Self::decode_current_state(&resp.control)),
-            e => Err(Error::Protocol(e)),
-        }
+    pub fn selected_i2c_bus(&self) -> u32 {
+        self.selected_i2c_bus
     }
 
-    pub async fn read(&mut self) -> Result<CurrentControlState, Error> {
-        let mut req = protobuf::new_request();
-        req.control = Some(ControlRequest::default());
-        self.io.send(req).await?;
-
-        let resp = self.read_responce().await?;
-        match Status::from_i32(resp.global_status).unwrap() {
-            Status::Ok => Ok(Self::decode_current_state(&resp.control)),
-            e => Err(Error::Protocol(e)),
+    /// I2C buses of the device, enumerated on the first call
+    pub async fn i2c_buses(&mut self) -> Result<Vec<I2CBus>, Error> {
+        match &self.i2c_buses {
+            Some(buses) => Ok(buses.clone()),
+            None => self.enumerate_i2c_buses().await,
         }
     }
 
-    pub fn select_i2c_bus(&mut self, bus_id: u32) {
-        self.selected_i2c_bus = bus_id;
+    /// Capabilities of `bus_id` if buses were already enumerated
+    pub fn i2c_bus_info(&self, bus_id: u32) -> Option<I2CBus> {
+        self.i2c_buses
+            .as_ref()?
+            .iter()
+            .find(|b| b.id == bus_id)
+            .copied()
     }
 
+    /// Ask the device for its I2C buses, the result is cached for bus validation
     pub async fn enumerate_i2c_buses(&mut self) -> Result<Vec<I2CBus>, Error> {
-        let mut req = protobuf::new_request();
+        let req = enumerate_request();
+        let req_id = req.id;
 
-        let mut i2c_request = protobuf::messages::I2cRequest::default();
-        i2c_request.request = Some(i2c_request::Request::Enumerate(
-            protobuf::messages::Empty {},
-        ));
+        async {
+            let buses = enumerate_result(self.exchange(req, self.i2c_timeout).await?)?;
+            self.i2c_buses = Some(buses.clone());
+            Ok(buses)
+        }
+        .await
+        .map_err(|e: Error| e.context("enumerate I2C buses", req_id))
+    }
+}
 
-        req.i2c.replace(i2c_request);
+fn global_status(resp: &protobuf::messages::Response) -> Result<Status, Error> {
+    protobuf::enum_value(resp.global_status, Status::from_i32, "Status")
+}
 
-        self.io.send(req).await?;
-        let resp = self.read_responce().await?;
+fn decode_current_state(
+    ctrl: &Option<protobuf::messages::ControlResponse>,
+) -> Result<CurrentControlState, Error> {
+    let ctrl = ctrl.as_ref().ok_or(Error::MissingField("control"))?;
+    Ok(CurrentControlState {
+        valve: protobuf::enum_value(ctrl.valve_state, ValveState::from_i32, "ValveState")?,
+        channel: ctrl.selected_channel,
+        camera: protobuf::enum_value(ctrl.actuator_state, CameraState::from_i32, "ActuatorState")?,
+    })
+}
 
-        if resp.global_status != Status::Ok as i32 {
-            return Err(Error::Protocol(
-                Status::from_i32(resp.global_status).unwrap(),
-            ));
-        }
+fn read_request() -> protobuf::messages::Request {
+    let mut req = protobuf::new_request();
+    req.control = Some(ControlRequest::default());
+    req
+}
 
-        match resp.i2c {
-            Some(protobuf::messages::I2cResponse {
-                response:
-                    Some(protobuf::messages::i2c_response::Response::Enumerate(
-                        protobuf::messages::I2cEnumerateResponse { buses },
-                    )),
-            }) => Ok(buses
-                .into_iter()
-                .map(|b| I2CBus {
-                    id: b.bus,
-                    speed: b.max_speed,
-                })
-                .collect()),
-            _ => Err(Error::Protocol(Status::I2c)),
-        }
+fn write_request(request: &impl ControlState) -> protobuf::messages::Request {
+    let mut req = protobuf::new_request();
+
+    let mut ctrl = protobuf::messages::ControlRequest::default();
+
+    if let Some(valve) = request.valve() {
+        ctrl.valve_state = Some(valve as i32);
+    }
+    if let Some(camera) = request.camera() {
+        ctrl.actuator_state = Some(camera as i32);
     }
+    ctrl.select_channel = request.channel();
+
+    req.control = Some(ctrl);
+    req
 }
 
-impl embedded_hal_async::i2c::ErrorType for LaserSetup {
-    type Error = Error;
+fn control_result(resp: protobuf::messages::Response) -> Result<CurrentControlState, Error> {
+    match global_status(&resp)? {
+        Status::Ok => decode_current_state(&resp.control),
+        e => Err(Error::Protocol(e)),
+    }
 }
 
-impl I2c for LaserSetup {
-    async fn transaction(
-        &mut self,
-        address: u8,
-        operations: &mut [embedded_hal_async::i2c::Operation<'_>],
-    ) -> Result<(), Self::Error> {
-        use crate::protobuf::messages::{
-            i2c_response::Response, i2c_result::Operation, I2cOperation, I2cResult,
-        };
-        use protobuf::messages::i2c_operation::Operation as I2cOperationType;
-
-        fn status2_err(status: i32) -> Result<(), Error> {
-            match I2cResultCode::from_i32(status).unwrap() {
-                I2cResultCode::I2cInvalidBus => Err(Error::I2C(ErrorKind::Bus)),
-                I2cResultCode::I2cTooLongData => Err(Error::I2C(ErrorKind::Overrun)),
-                I2cResultCode::I2cNak => Err(Error::I2C(ErrorKind::NoAcknowledge(
-                    embedded_hal_async::i2c::NoAcknowledgeSource::Unknown,
-                ))),
-                _ => Ok(()),
-            }
-        }
+fn enumerate_request() -> protobuf::messages::Request {
+    let mut req = protobuf::new_request();
 
-        use protobuf::messages::I2cResultCode;
+    let mut i2c_request = protobuf::messages::I2cRequest::default();
+    i2c_request.request = Some(i2c_request::Request::Enumerate(
+        protobuf::messages::Empty {},
+    ));
 
-        let mut req = protobuf::new_request();
+    req.i2c.replace(i2c_request);
+    req
+}
+
+fn enumerate_result(resp: protobuf::messages::Response) -> Result<Vec<I2CBus>, Error> {
+    match global_status(&resp)? {
+        Status::Ok => {}
+        e => return Err(Error::Protocol(e)),
+    }
 
-        let req_operations: Vec<I2cOperation> = operations
+    match resp.i2c {
+        Some(protobuf::messages::I2cResponse {
+            response:
+                Some(protobuf::messages::i2c_response::Response::Enumerate(
+                    protobuf::messages::I2cEnumerateResponse { buses },
+                )),
+        }) => Ok(buses
             .into_iter()
-            .map(|o| match o {
-                embedded_hal_async::i2c::Operation::Write(w) => I2cOperation {
-                    operation: Some(I2cOperationType::Write(
-                        protobuf::messages::I2cWriteRequest {
-                            address: address as u32,
-                            data: w.to_vec(),
-                        },
-                    )),
-                },
-                embedded_hal_async::i2c::Operation::Read(r) => I2cOperation {
-                    operation: Some(I2cOperationType::Read(protobuf::messages::I2cReadRequest {
-                        address: address as u32,
-                        length: r.len() as u32,
-                    })),
-                },
+            .map(|b| I2CBus {
+                id: b.bus,
+                speed: b.max_speed,
             })
-            .collect();
-
-        let req_len = req_operations.len();
-
-        let req_sequence = protobuf::messages::I2cRequest {
-            request: Some(protobuf::messages::i2c_request::Request::Sequence(
-                protobuf::messages::I2cSequence {
-                    bus: self.selected_i2c_bus,
-                    address: address as u32,
-                    operations: req_operations,
-                },
-            )),
-        };
-
-        req.i2c = Some(req_sequence);
-
-        self.io.send(req).await?;
-        let resp = self.read_responce().await?;
-
-        match Status::from_i32(resp.global_status).unwrap() {
-            Status::Ok => {}
-            Status::I2c => {
-                if let Some(r) = resp.i2c {
-                    if let Some(crate::protobuf::messages::i2c_response::Response::Sequence(s)) =
-                        r.response
-                    {
-                        let is_nak = s.operations.iter().any(|o| match o.operation {
-                            Some(Operation::Write(status)) => {
-                                status == I2cResultCode::I2cNak as i32
-                            }
-                            Some(Operation::Read(protobuf::messages::I2cReadResponse {
-                                status,
-                                ..
-                            })) => status == I2cResultCode::I2cNak as i32,
-                            _ => false,
-                        });
-                        if is_nak {
-                            return Err(Error::I2C(ErrorKind::NoAcknowledge(
-                                embedded_hal_async::i2c::NoAcknowledgeSource::Unknown,
-                            )));
-                        }
-                    }
-                }
-                return Err(Error::I2C(ErrorKind::Bus));
-            }
-            e => return Err(Error::Protocol(e)),
-        }
-
-        match resp.i2c {
-            Some(protobuf::messages::I2cResponse {
-                response:
-                    Some(Response::Sequence(protobuf::messages::I2cSequenceResult {
-                        operations: res_operations,
-                        bus,
-                        address,
-                    })),
-            }) => {
-                if req_len != res_operations.len()
-                    || bus != self.selected_i2c_bus
-                    || address != address as u32
-                {
-                    return Err(Error::Protocol(Status::I2c));
-                }
-
-                for v in operations.iter_mut().zip(res_operations.iter()) {
-                    match v {
-                        (
-                            embedded_hal_async::i2c::Operation::Read(buf),
-                            I2cResult {
-                                operation:
-                                    Some(Operation::Read(protobuf::messages::I2cReadResponse {
-                                        data,
-                                        status,
-                                    })),
-                            },
-                        ) => {
-                            status2_err(*status)?;
-                            buf.copy_from_slice(data);
-                        }
-                        (
-                            embedded_hal_async::i2c::Operation::Write(_),
-                            I2cResult {
-                                operation: Some(Operation::Write(status)),
-                            },
-                        ) => {
-                            status2_err(*status)?;
-                        }
-                        _ => return Err(Error::Protocol(Status::I2c)),
-                    }
-                }
-            }
-            _ => return Err(Error::Protocol(Status::I2c)),
-        }
-        Ok(())
+            .collect()),
+        _ => Err(Error::UnexpectedResponse {
+            expected: "I2C bus list",
+        }),
     }
 }
@@ -0,0 +1,279 @@
+use std::any::Any;
+
+/// Downcast support for [`VirtualI2cDevice`] trait objects
+pub trait AsAny {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// I2C slave attached to [`MockDevice`](super::MockDevice)
+///
+/// Every operation of a sequence is a separate call, returning `false` makes the device
+/// not acknowledge it.
+pub trait VirtualI2cDevice: AsAny + Send + 'static {
+    /// Master writes `data`
+    fn write(&mut self, data: &[u8]) -> bool;
+    /// Master reads `buf.len()` bytes
+    fn read(&mut self, buf: &mut [u8]) -> bool;
+}
+
+/// Behaviour of an address or a whole bus overriding the attached devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cFault {
+    /// Address phase is not acknowledged
+    Nak,
+    /// Laser-setup does not answer the request at all
+    Timeout,
+}
+
+/// Generic register file: the first written byte sets register pointer, following writes
+/// and reads access registers from the pointer with auto-increment, wrapping at the end
+#[derive(Debug, Clone)]
+pub struct RegisterFile {
+    registers: Vec<u8>,
+    pointer: usize,
+}
+
+impl RegisterFile {
+    pub fn new(size: usize) -> Self {
+        Self::from(vec![0; size])
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8] {
+        &mut self.registers
+    }
+
+    fn next(&mut self) -> Option<&mut u8> {
+        if self.registers.is_empty() {
+            return None;
+        }
+        let pos = self.pointer % self.registers.len();
+        self.pointer = pos + 1;
+        self.registers.get_mut(pos)
+    }
+}
+
+impl From<Vec<u8>> for RegisterFile {
+    fn from(registers: Vec<u8>) -> Self {
+        Self {
+            registers,
+            pointer: 0,
+        }
+    }
+}
+
+impl VirtualI2cDevice for RegisterFile {
+    fn write(&mut self, data: &[u8]) -> bool {
+        if let Some((reg, data)) = data.split_first() {
+            self.pointer = *reg as usize;
+            for b in data {
+                if let Some(r) = self.next() {
+                    *r = *b;
+                }
+            }
+        }
+        true
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> bool {
+        for b in buf.iter_mut() {
+            *b = self.next().map_or(0, |r| *r);
+        }
+        true
+    }
+}
+
+/// 24Cxx serial EEPROM
+///
+/// Memory address is 1 byte for chips up to 256 bytes (24C01, 24C02) and 2 bytes for
+/// larger ones, page writes wrap inside the page like on the real chip.
+#[derive(Debug, Clone)]
+pub struct Eeprom24 {
+    memory: Vec<u8>,
+    page_size: usize,
+    address: usize,
+}
+
+impl Eeprom24 {
+    /// Erased (0xFF) chip of `size` bytes with `page_size` bytes write page
+    ///
+    /// Panics unless `size` is a non-zero multiple of non-zero `page_size`, as on real chips.
+    pub fn new(size: usize, page_size: usize) -> Self {
+        assert!(
+            size > 0 && size.checked_rem(page_size) == Some(0),
+            "EEPROM of {} bytes can't have {}-byte pages",
+            size,
+            page_size
+        );
+        Self {
+            memory: vec![0xFF; size],
+            page_size,
+            address: 0,
+        }
+    }
+
+    /// 24C02: 256 bytes, 8-byte pages
+    pub fn c02() -> Self {
+        Self::new(256, 8)
+    }
+
+    /// 24C32: 4 KiB, 32-byte pages
+    pub fn c32() -> Self {
+        Self::new(4096, 32)
+    }
+
+    /// 24C256: 32 KiB, 64-byte pages
+    pub fn c256() -> Self {
+        Self::new(32768, 64)
+    }
+
+    fn address_bytes(&self) -> usize {
+        if self.memory.len() > 256 {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+}
+
+impl VirtualI2cDevice for Eeprom24 {
+    fn write(&mut self, data: &[u8]) -> bool {
+        let address_bytes = self.address_bytes();
+        if data.len() < address_bytes {
+            // address incomplete, chip ignores the write
+            return true;
+        }
+
+        let (address, data) = data.split_at(address_bytes);
+        self.address = address
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize)
+            % self.memory.len();
+
+        let page_start = self.address - self.address % self.page_size;
+        for b in data {
+            self.memory[self.address] = *b;
+            self.address = page_start + (self.address + 1 - page_start) % self.page_size;
+        }
+        true
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> bool {
+        for b in buf.iter_mut() {
+            *b = self.memory[self.address];
+            self.address = (self.address + 1) % self.memory.len();
+        }
+        true
+    }
+}
+
+/// Frequency meter as read by `examples/freq-reader.rs`: register file with frequency
+/// as little-endian `f32` at [`FreqMeter::FREQUENCY_REG`]
+#[derive(Debug, Clone)]
+pub struct FreqMeter {
+    registers: RegisterFile,
+}
+
+impl FreqMeter {
+    /// Default I2C address
+    pub const ADDRESS: u8 = 0x0B;
+    pub const FREQUENCY_REG: u8 = 0x08;
+
+    const REGISTERS_COUNT: usize = 16;
+
+    pub fn new(frequency: f32) -> Self {
+        let mut res = Self {
+            registers: RegisterFile::new(Self::REGISTERS_COUNT),
+        };
+        res.set_frequency(frequency);
+        res
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        let reg = Self::FREQUENCY_REG as usize;
+        self.registers.registers_mut()[reg..reg + std::mem::size_of::<f32>()]
+            .copy_from_slice(&frequency.to_le_bytes());
+    }
+
+    pub fn frequency(&self) -> f32 {
+        let reg = Self::FREQUENCY_REG as usize;
+        let mut bytes = [0u8; std::mem::size_of::<f32>()];
+        bytes.copy_from_slice(&self.registers.registers()[reg..reg + std::mem::size_of::<f32>()]);
+        f32::from_le_bytes(bytes)
+    }
+}
+
+impl VirtualI2cDevice for FreqMeter {
+    fn write(&mut self, data: &[u8]) -> bool {
+        // frequency is measured, not written: only register pointer is accepted
+        if data.len() > 1 {
+            return false;
+        }
+        self.registers.write(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> bool {
+        self.registers.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eeprom_page_wrap() {
+        let mut eeprom = Eeprom24::c02();
+
+        // written from 0x0E, wraps to the start of 8-byte page 0x08
+        assert!(eeprom.write(&[0x0E, 1, 2, 3, 4]));
+        assert_eq!(
+            eeprom.memory()[0x08..0x10],
+            [3, 4, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2]
+        );
+
+        // reads are not limited to the page
+        let mut buf = [0u8; 3];
+        assert!(eeprom.write(&[0x0F]));
+        assert!(eeprom.read(&mut buf));
+        assert_eq!(buf, [2, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn eeprom_last_page_wrap() {
+        let mut eeprom = Eeprom24::new(96, 8);
+
+        assert!(eeprom.write(&[95, 1, 2]));
+        assert_eq!(eeprom.memory()[88], 2);
+        assert_eq!(eeprom.memory()[95], 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn eeprom_partial_page() {
+        Eeprom24::new(100, 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn eeprom_zero_page() {
+        Eeprom24::new(256, 0);
+    }
+}
@@ -0,0 +1,745 @@
+//! In-process Laser-setup simulator
+//!
+//! [`MockDevice`] implements the firmware side of the protocol on any byte stream and
+//! records every request it receives, so code using [`LaserSetup`] can be tested without
+//! the fixture:
+//!
+//! ```no_run
+//! # async fn test() -> Result<(), laser_setup_interface::Error> {
+//! use laser_setup_interface::mock::{FreqMeter, MockDevice, Reply, RequestKind};
+//!
+//! let device = MockDevice::new();
+//! device.add_i2c_bus(0, 100_000);
+//! device.attach_i2c_device(0, FreqMeter::ADDRESS, FreqMeter::new(32768.0));
+//!
+//! let mut laser = device.connect(std::time::Duration::from_millis(100));
+//! laser.read().await?;
+//!
+//! device.script(Reply::NoAnswer);
+//! assert!(laser.read().await.is_err());
+//!
+//! assert!(matches!(device.received()[0].kind, RequestKind::Control { .. }));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_util::codec::Decoder;
+
+use crate::protobuf::messages::{
+    i2c_operation, i2c_request, i2c_response, i2c_result, ControlResponse, I2cEnumerateResponse,
+    I2cOperation, I2cReadResponse, I2cResponse, I2cResult, I2cSequence, I2cSequenceResult, Request,
+    Response, Status,
+};
+use crate::protobuf::{self, protobuf_md_codec::DeviceCodec};
+use crate::{CameraState, CurrentControlState, I2CBus, I2cResultCode, LaserSetup, ValveState};
+
+mod i2c_devices;
+pub use i2c_devices::{AsAny, Eeprom24, FreqMeter, I2cFault, RegisterFile, VirtualI2cDevice};
+
+/// Size of the in-memory duplex pipe used by [`MockDevice::connect`]
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How the device answers the next request, see [`MockDevice::script`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Answer from the simulated state
+    Normal,
+    /// Swallow request, client gets timeout
+    NoAnswer,
+    /// Answer normally after delay
+    Delay(Duration),
+    /// Answer normally twice
+    Duplicate,
+    /// Answer with wrong request id only
+    WrongId,
+    /// Answer normally, but pretend to be another device
+    Device {
+        device_id: u32,
+        protocol_version: u32,
+    },
+    /// Send raw bytes, then answer normally
+    Raw(Vec<u8>),
+    /// Answer I2C sequence as if operation `index` was not acknowledged
+    I2cNak { index: usize },
+}
+
+/// One I2C operation of a received request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum I2cOp {
+    Write(Vec<u8>),
+    /// Read of `n` bytes
+    Read(usize),
+}
+
+/// What the client asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    /// Control request, `None` fields are not changed
+    Control {
+        valve: Option<ValveState>,
+        camera: Option<CameraState>,
+        channel: Option<u32>,
+    },
+    I2cEnumerate,
+    I2cSequence {
+        bus: u32,
+        address: u32,
+        operations: Vec<I2cOp>,
+    },
+    /// Request without any payload
+    Empty,
+}
+
+/// Request received by [`MockDevice`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedRequest {
+    pub id: u32,
+    pub kind: RequestKind,
+}
+
+impl From<&Request> for ReceivedRequest {
+    fn from(req: &Request) -> Self {
+        let kind = if let Some(ctrl) = &req.control {
+            RequestKind::Control {
+                valve: ctrl.valve_state.and_then(ValveState::from_i32),
+                camera: ctrl.actuator_state.and_then(CameraState::from_i32),
+                channel: ctrl.select_channel,
+            }
+        } else {
+            match req.i2c.as_ref().and_then(|i2c| i2c.request.as_ref()) {
+                Some(i2c_request::Request::Enumerate(_)) => RequestKind::I2cEnumerate,
+                Some(i2c_request::Request::Sequence(seq)) => RequestKind::I2cSequence {
+                    bus: seq.bus,
+                    address: seq.address,
+                    operations: seq
+                        .operations
+                        .iter()
+                        .filter_map(|o| match &o.operation {
+                            Some(i2c_operation::Operation::Write(w)) => {
+                                Some(I2cOp::Write(w.data.clone()))
+                            }
+                            Some(i2c_operation::Operation::Read(r)) => {
+                                Some(I2cOp::Read(r.length as usize))
+                            }
+                            None => None,
+                        })
+                        .collect(),
+                },
+                None => RequestKind::Empty,
+            }
+        };
+
+        Self { id: req.id, kind }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    control: CurrentControlState,
+    buses: Vec<I2CBus>,
+    i2c_devices: HashMap<(u32, u8), Box<dyn VirtualI2cDevice>>,
+    /// Faults of a single address or, with `None` address, of the whole bus
+    i2c_faults: HashMap<(u32, Option<u8>), I2cFault>,
+    /// Longer I2C operations are rejected with `I2cTooLongData`
+    max_i2c_payload: Option<usize>,
+
+    script: VecDeque<Reply>,
+    received: Vec<ReceivedRequest>,
+}
+
+fn operation_length(op: &I2cOperation) -> usize {
+    match &op.operation {
+        Some(i2c_operation::Operation::Write(w)) => w.data.len(),
+        Some(i2c_operation::Operation::Read(r)) => r.length as usize,
+        None => 0,
+    }
+}
+
+/// Success code of I2C operation, the first value of `I2cResultCode`
+fn i2c_ok() -> i32 {
+    I2cResultCode::default() as i32
+}
+
+impl MockState {
+    /// `None` if the request must not be answered
+    fn answer(&mut self, req: &Request, nak_at: Option<usize>) -> Option<Response> {
+        let mut resp = protobuf::new_response(req);
+
+        if let Some(ctrl) = &req.control {
+            if let Some(valve) = ctrl.valve_state.and_then(ValveState::from_i32) {
+                self.control.valve = valve;
+            }
+            if let Some(camera) = ctrl.actuator_state.and_then(CameraState::from_i32) {
+                self.control.camera = camera;
+            }
+            if let Some(channel) = ctrl.select_channel {
+                if channel < crate::CHANNELS_COUNT {
+                    self.control.channel = channel;
+                }
+            }
+
+            resp.control = Some(ControlResponse {
+                valve_state: self.control.valve as i32,
+                actuator_state: self.control.camera as i32,
+                selected_channel: self.control.channel,
+            });
+        }
+
+        match req.i2c.as_ref().and_then(|i2c| i2c.request.as_ref()) {
+            Some(i2c_request::Request::Enumerate(_)) => {
+                let mut list = I2cEnumerateResponse::default();
+                list.buses.resize_with(self.buses.len(), Default::default);
+                for (dest, bus) in list.buses.iter_mut().zip(self.buses.iter()) {
+                    dest.bus = bus.id;
+                    dest.max_speed = bus.speed;
+                }
+                resp.i2c = Some(I2cResponse {
+                    response: Some(i2c_response::Response::Enumerate(list)),
+                });
+            }
+            Some(i2c_request::Request::Sequence(seq)) => {
+                let (ok, result) = self.i2c_sequence(seq, nak_at)?;
+                if !ok {
+                    resp.global_status = Status::I2c as i32;
+                }
+                resp.i2c = Some(I2cResponse {
+                    response: Some(i2c_response::Response::Sequence(result)),
+                });
+            }
+            None => {}
+        }
+
+        Some(resp)
+    }
+
+    /// Execute sequence until the first failed operation, `None` on timeout fault
+    fn i2c_sequence(
+        &mut self,
+        seq: &I2cSequence,
+        nak_at: Option<usize>,
+    ) -> Option<(bool, I2cSequenceResult)> {
+        let address = seq.address as u8;
+        let fault = self
+            .i2c_faults
+            .get(&(seq.bus, Some(address)))
+            .or_else(|| self.i2c_faults.get(&(seq.bus, None)))
+            .copied();
+
+        let mut device = if fault == Some(I2cFault::Timeout) {
+            return None;
+        } else if !self.buses.iter().any(|b| b.id == seq.bus) {
+            Err(I2cResultCode::I2cInvalidBus)
+        } else if seq
+            .operations
+            .iter()
+            .any(|op| operation_length(op) > self.max_i2c_payload.unwrap_or(usize::MAX))
+        {
+            // firmware checks buffer size before touching the bus
+            Err(I2cResultCode::I2cTooLongData)
+        } else if fault == Some(I2cFault::Nak) {
+            Err(I2cResultCode::I2cNak)
+        } else {
+            self.i2c_devices
+                .get_mut(&(seq.bus, address))
+                .ok_or(I2cResultCode::I2cNak)
+        };
+
+        let mut result = I2cSequenceResult {
+            bus: seq.bus,
+            address: seq.address,
+            operations: vec![],
+        };
+
+        for (index, op) in seq.operations.iter().enumerate() {
+            let nak = I2cResultCode::I2cNak as i32;
+            let (status, res) = match (&mut device, &op.operation) {
+                (Err(code), Some(i2c_operation::Operation::Write(_))) => {
+                    (*code as i32, i2c_result::Operation::Write(*code as i32))
+                }
+                (Err(code), Some(i2c_operation::Operation::Read(_))) => (
+                    *code as i32,
+                    i2c_result::Operation::Read(I2cReadResponse {
+                        status: *code as i32,
+                        data: vec![],
+                    }),
+                ),
+                (Ok(device), Some(i2c_operation::Operation::Write(w))) => {
+                    let status = if nak_at != Some(index) && device.write(&w.data) {
+                        i2c_ok()
+                    } else {
+                        nak
+                    };
+                    (status, i2c_result::Operation::Write(status))
+                }
+                (Ok(device), Some(i2c_operation::Operation::Read(r))) => {
+                    let mut data = vec![0; r.length as usize];
+                    let status = if nak_at != Some(index) && device.read(&mut data) {
+                        i2c_ok()
+                    } else {
+                        data.clear();
+                        nak
+                    };
+                    (
+                        status,
+                        i2c_result::Operation::Read(I2cReadResponse { status, data }),
+                    )
+                }
+                (_, None) => break,
+            };
+
+            result.operations.push(I2cResult {
+                operation: Some(res),
+            });
+
+            if status != i2c_ok() {
+                return Some((false, result));
+            }
+        }
+
+        Some((true, result))
+    }
+}
+
+/// Simulated Laser-setup, cheap to clone, all clones share one device state
+#[derive(Clone, Default)]
+pub struct MockDevice {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Add I2C bus reported by `enumerate_i2c_buses()`
+    pub fn add_i2c_bus(&self, id: u32, speed: u32) {
+        self.state().buses.push(I2CBus { id, speed });
+    }
+
+    /// Firmware buffer size, longer I2C operations fail with `I2cTooLongData`
+    pub fn set_max_i2c_payload(&self, max: Option<usize>) {
+        self.state().max_i2c_payload = max;
+    }
+
+    /// Attach `device` to `bus` at `address`, addresses without device NAK
+    pub fn attach_i2c_device(&self, bus: u32, address: u8, device: impl VirtualI2cDevice) {
+        self.state()
+            .i2c_devices
+            .insert((bus, address), Box::new(device));
+    }
+
+    pub fn detach_i2c_device(&self, bus: u32, address: u8) {
+        self.state().i2c_devices.remove(&(bus, address));
+    }
+
+    /// Access device attached at `bus`/`address` if it is of type `T`
+    pub fn with_i2c_device<T: VirtualI2cDevice, R>(
+        &self,
+        bus: u32,
+        address: u8,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Option<R> {
+        self.state()
+            .i2c_devices
+            .get_mut(&(bus, address))
+            // as_mut(): Box itself implements AsAny too
+            .and_then(|d| d.as_mut().as_any_mut().downcast_mut::<T>())
+            .map(f)
+    }
+
+    /// Override behaviour of `address` on `bus`, or of the whole bus if `address` is `None`
+    pub fn set_i2c_fault(&self, bus: u32, address: Option<u8>, fault: Option<I2cFault>) {
+        let mut state = self.state();
+        match fault {
+            Some(fault) => state.i2c_faults.insert((bus, address), fault),
+            None => state.i2c_faults.remove(&(bus, address)),
+        };
+    }
+
+    pub fn control_state(&self) -> CurrentControlState {
+        self.state().control
+    }
+
+    pub fn set_control_state(&self, state: CurrentControlState) {
+        self.state().control = state;
+    }
+
+    /// Queue reply for the next request, requests without scripted reply are answered normally
+    pub fn script(&self, reply: Reply) {
+        self.state().script.push_back(reply);
+    }
+
+    /// All requests received so far
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.state().received.clone()
+    }
+
+    /// Requests received since the last call
+    pub fn take_received(&self) -> Vec<ReceivedRequest> {
+        std::mem::take(&mut self.state().received)
+    }
+
+    /// Serve requests from `io` until it is closed
+    pub fn serve<IO>(&self, io: IO) -> JoinHandle<()>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let device = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = device.run(io).await {
+                log::debug!("Mock device stopped: {}", e);
+            }
+        })
+    }
+
+    /// Create [`LaserSetup`] connected to this device through in-memory pipe
+    pub fn connect(&self, timeout: Duration) -> LaserSetup<DuplexStream> {
+        let (client, device) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+        self.serve(device);
+        LaserSetup::from_io(client, timeout)
+    }
+
+    async fn run<IO: AsyncRead + AsyncWrite + Unpin>(&self, io: IO) -> Result<(), crate::Error> {
+        let mut io = DeviceCodec::new().framed(io);
+
+        while let Some(req) = io.next().await {
+            let req = req?;
+
+            let reply = {
+                let received = ReceivedRequest::from(&req);
+                log::debug!("Request: {:?}", received);
+
+                let mut state = self.state();
+                state.received.push(received);
+                state.script.pop_front().unwrap_or(Reply::Normal)
+            };
+
+            let nak_at = match reply {
+                Reply::I2cNak { index } => Some(index),
+                _ => None,
+            };
+            let mut resp = match self.state().answer(&req, nak_at) {
+                Some(resp) => resp,
+                None => continue,
+            };
+
+            match reply {
+                Reply::Normal | Reply::I2cNak { .. } => io.send(resp).await?,
+                Reply::NoAnswer => {}
+                Reply::Delay(delay) => {
+                    tokio::time::sleep(delay).await;
+                    io.send(resp).await?;
+                }
+                Reply::Duplicate => {
+                    io.send(resp.clone()).await?;
+                    io.send(resp).await?;
+                }
+                Reply::WrongId => {
+                    resp.id = resp.id.wrapping_sub(1);
+                    io.send(resp).await?;
+                }
+                Reply::Device {
+                    device_id,
+                    protocol_version,
+                } => {
+                    resp.device_id = device_id;
+                    resp.protocol_version = protocol_version;
+                    io.send(resp).await?;
+                }
+                Reply::Raw(bytes) => {
+                    io.get_mut().write_all(&bytes).await?;
+                    io.send(resp).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_async::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+
+    use super::*;
+    use crate::{ControlState, Error, I2c};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    struct Command {
+        valve: Option<ValveState>,
+        channel: Option<u32>,
+        camera: Option<CameraState>,
+    }
+
+    impl ControlState for Command {
+        fn valve(&self) -> Option<ValveState> {
+            self.valve
+        }
+
+        fn channel(&self) -> Option<u32> {
+            self.channel
+        }
+
+        fn camera(&self) -> Option<CameraState> {
+            self.camera
+        }
+    }
+
+    fn device_with_buses() -> MockDevice {
+        let device = MockDevice::new();
+        device.add_i2c_bus(0, 100_000);
+        device.add_i2c_bus(1, 400_000);
+        device.attach_i2c_device(1, 0x50, Eeprom24::c02());
+        device
+    }
+
+    #[tokio::test]
+    async fn control_read_write() {
+        let device = MockDevice::new();
+        let mut laser = device.connect(TIMEOUT);
+
+        let state = laser.read().await.unwrap();
+        assert_eq!(state.channel, 0);
+
+        let vacuum = ValveState::from_i32(1).unwrap();
+        let state = laser
+            .write(&Command {
+                valve: Some(vacuum),
+                channel: Some(3),
+                camera: Some(CameraState::Open),
+            })
+            .await
+            .unwrap();
+        assert_eq!(state.valve, vacuum);
+        assert_eq!(state.channel, 3);
+        assert_eq!(state.camera, CameraState::Open);
+        assert_eq!(device.control_state().channel, 3);
+
+        // unset fields are kept
+        let state = laser
+            .write(&Command {
+                valve: None,
+                channel: Some(5),
+                camera: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!((state.valve, state.channel), (vacuum, 5));
+
+        let received = device.received();
+        assert_eq!(received.len(), 3);
+        assert_eq!(
+            received[1].kind,
+            RequestKind::Control {
+                valve: Some(vacuum),
+                camera: Some(CameraState::Open),
+                channel: Some(3),
+            }
+        );
+        assert_eq!(
+            received[2].kind,
+            RequestKind::Control {
+                valve: None,
+                camera: None,
+                channel: Some(5),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn enumerate() {
+        let device = device_with_buses();
+        let mut laser = device.connect(TIMEOUT);
+
+        let buses = laser.enumerate_i2c_buses().await.unwrap();
+        assert_eq!(
+            buses,
+            vec![
+                I2CBus {
+                    id: 0,
+                    speed: 100_000
+                },
+                I2CBus {
+                    id: 1,
+                    speed: 400_000
+                },
+            ]
+        );
+        assert_eq!(device.take_received()[0].kind, RequestKind::I2cEnumerate);
+
+        // cached, no second request
+        assert_eq!(laser.i2c_buses().await.unwrap(), buses);
+        assert!(device.take_received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn i2c_sequence() {
+        let device = device_with_buses();
+        let mut laser = device.connect(TIMEOUT);
+
+        laser
+            .transaction_on(1, 0x50, &mut [Operation::Write(&[0x10, 1, 2, 3])])
+            .await
+            .unwrap();
+        let mut buf = [0u8; 3];
+        laser
+            .transaction_on(
+                1,
+                0x50,
+                &mut [Operation::Write(&[0x10]), Operation::Read(&mut buf)],
+            )
+            .await
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(
+            device.with_i2c_device(1, 0x50, |e: &mut Eeprom24| e.memory()[0x10..0x13].to_vec()),
+            Some(vec![1, 2, 3])
+        );
+
+        // buses are enumerated before the first transaction
+        assert_eq!(device.received()[0].kind, RequestKind::I2cEnumerate);
+        assert_eq!(
+            device.received()[2].kind,
+            RequestKind::I2cSequence {
+                bus: 1,
+                address: 0x50,
+                operations: vec![I2cOp::Write(vec![0x10]), I2cOp::Read(3)],
+            }
+        );
+
+        // nothing at this address
+        let err = laser
+            .transaction_on(0, 0x50, &mut [Operation::Read(&mut buf)])
+            .await;
+        assert!(matches!(
+            err,
+            Err(Error::I2C(ErrorKind::NoAcknowledge(
+                NoAcknowledgeSource::Address
+            )))
+        ));
+
+        laser.select_i2c_bus(1).await.unwrap();
+        laser
+            .write_read(0x50, &[0x11], &mut buf[..1])
+            .await
+            .unwrap();
+        assert_eq!(buf[0], 2);
+
+        // unknown bus is refused without a request
+        let sent = device.received().len();
+        let err = laser
+            .transaction_on(7, 0x50, &mut [Operation::Read(&mut buf)])
+            .await
+            .unwrap_err();
+        assert!(matches!(err.root(), Error::InvalidBus { bus: 7, .. }));
+        assert_eq!(device.received().len(), sent);
+    }
+
+    #[tokio::test]
+    async fn scripted_no_answer_and_delay() {
+        let device = MockDevice::new();
+        let mut laser = device.connect(TIMEOUT);
+
+        device.script(Reply::NoAnswer);
+        let err = laser.read().await.unwrap_err();
+        assert!(matches!(err.root(), Error::Timeout { .. }));
+
+        device.script(Reply::Delay(TIMEOUT / 5));
+        laser.read().await.unwrap();
+
+        // late answer to the timed out request is discarded
+        device.script(Reply::Delay(TIMEOUT * 2));
+        assert!(laser.read().await.is_err());
+        laser.read().await.unwrap();
+        assert_eq!(laser.stale_frames(), 1);
+
+        assert_eq!(device.received().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn scripted_duplicate_and_wrong_id() {
+        let device = MockDevice::new();
+        let mut laser = device.connect(TIMEOUT);
+
+        device.script(Reply::Duplicate);
+        laser.read().await.unwrap();
+        laser.read().await.unwrap();
+        assert_eq!(laser.stale_frames(), 1);
+
+        device.script(Reply::WrongId);
+        let err = laser.read().await.unwrap_err();
+        assert!(matches!(err.root(), Error::Timeout { .. }));
+        assert_eq!(laser.stale_frames(), 2);
+        laser.read().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scripted_device_and_raw() {
+        let device = MockDevice::new();
+        let mut laser = device.connect(TIMEOUT);
+
+        device.script(Reply::Device {
+            device_id: 0x1234,
+            protocol_version: 1,
+        });
+        let err = laser.handshake().await.unwrap_err();
+        assert!(matches!(
+            err.root(),
+            Error::IncompatibleDevice {
+                device_id: 0x1234,
+                ..
+            }
+        ));
+
+        device.script(Reply::Raw(vec![0x00, 0xFF, 0x42]));
+        laser.read().await.unwrap();
+        assert_eq!(laser.handshake().await.unwrap().protocol_version, 1);
+    }
+
+    #[tokio::test]
+    async fn scripted_i2c_nak() {
+        let device = device_with_buses();
+        let mut laser = device.connect(TIMEOUT);
+        laser.enumerate_i2c_buses().await.unwrap();
+
+        device.script(Reply::I2cNak { index: 1 });
+        let mut buf = [0u8; 2];
+        let results = laser
+            .transaction_detailed_on(
+                1,
+                0x50,
+                &mut [Operation::Write(&[0]), Operation::Read(&mut buf)],
+            )
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].code, Some(I2cResultCode::I2cNak));
+
+        // refused write: address or data phase is not reported
+        device.script(Reply::I2cNak { index: 1 });
+        let err = laser
+            .transaction_on(
+                1,
+                0x50,
+                &mut [Operation::Write(&[0]), Operation::Write(&[1])],
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.root(),
+            Error::I2C(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown))
+        ));
+    }
+}
@@ -4,16 +4,45 @@ pub mod protobuf_md_codec;
 
 pub mod messages;
 
+use std::sync::atomic::{AtomicU32, Ordering};
+
 pub use pb_error::Error;
 
-pub fn new_request() -> messages::Request {
-    let mut rng = rand::thread_rng();
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
 
+/// New request with unique monotonic id
+pub fn new_request() -> messages::Request {
     messages::Request {
-        id: rand::Rng::gen(&mut rng),
+        id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
         device_id: messages::Info::LaserSetupId as u32,
         protocol_version: messages::Info::ProtocolVersion as u32,
 
         ..Default::default()
     }
-}
\ No newline at end of file
+}
+
+/// Empty response to `req` with Laser-setup identity and `Status::Ok`
+pub fn new_response(req: &messages::Request) -> messages::Response {
+    messages::Response {
+        id: req.id,
+        device_id: messages::Info::LaserSetupId as u32,
+        protocol_version: messages::Info::ProtocolVersion as u32,
+        global_status: messages::Status::Ok as i32,
+
+        ..Default::default()
+    }
+}
+
+/// Is device with protocol version `version` understood by this library?
+pub fn is_compatible_protocol(version: u32) -> bool {
+    version == messages::Info::ProtocolVersion as u32
+}
+
+/// Convert raw protobuf enum value, unknown values (e.g. from newer firmware) are errors
+pub fn enum_value<T>(
+    value: i32,
+    from_i32: impl FnOnce(i32) -> Option<T>,
+    name: &'static str,
+) -> Result<T, Error> {
+    from_i32(value).ok_or(Error::UnknownEnumValue { name, value })
+}
@@ -1,16 +1,227 @@
-use embedded_hal_async::i2c::ErrorKind;
+use std::fmt;
 
+use embedded_hal_async::i2c::ErrorKind;
 
 #[derive(Debug)]
 pub enum Error {
     EncodeError(prost::EncodeError),
     DecoderError(prost::DecodeError),
     IoError(std::io::Error),
+    Serial(tokio_serial::Error),
 
     UnexpectedEndOfStream,
-    Timeout,
+    /// No answer in time, `request_id` is the request being waited for
+    Timeout {
+        request_id: Option<u32>,
+    },
+    /// Answer came from something that is not a compatible Laser-setup
+    IncompatibleDevice {
+        device_id: u32,
+        protocol_version: u32,
+    },
     Protocol(super::messages::Status),
+    /// Required field is absent in response
+    MissingField(&'static str),
+    /// Enum value unknown to this library version
+    UnknownEnumValue {
+        name: &'static str,
+        value: i32,
+    },
+    /// Response does not contain the part answering the request
+    UnexpectedResponse {
+        expected: &'static str,
+    },
+    /// Number of I2C results differs from number of requested operations
+    I2cLengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// I2C result is for another bus
+    BusMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// I2C result is for another device address
+    AddressMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// I2C result `index` is of other kind (read/write) than requested operation
+    I2cOperationMismatch {
+        index: usize,
+    },
+    /// I2C read `index` returned other number of bytes than requested
+    ReadLengthMismatch {
+        index: usize,
+        expected: usize,
+        actual: usize,
+    },
     I2C(ErrorKind),
+    /// Device has no I2C bus `bus`
+    InvalidBus {
+        bus: u32,
+        available: Vec<u32>,
+    },
+    /// I2C operation `index` is longer than the firmware accepts
+    I2cPayloadTooLong {
+        index: usize,
+        length: usize,
+        max: usize,
+    },
+    /// Register address does not fit register address width of the device
+    InvalidRegister {
+        register: u16,
+    },
+    /// Memory transfer ends at `end`, beyond memory addressable by its offset width
+    MemoryOverflow {
+        end: u64,
+        capacity: u64,
+    },
+    /// Memory offset width is not 1..=4 bytes or page size is zero
+    InvalidMemoryLayout {
+        address_width: usize,
+        page_size: Option<usize>,
+    },
+
+    /// `source` happened while performing `operation` with request `request_id`
+    Context {
+        operation: &'static str,
+        request_id: u32,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Attach operation context
+    pub fn context(self, operation: &'static str, request_id: u32) -> Self {
+        Error::Context {
+            operation,
+            request_id,
+            source: Box::new(self),
+        }
+    }
+
+    /// Error without context wrappers
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EncodeError(e) => write!(f, "failed to encode request: {}", e),
+            Error::DecoderError(e) => write!(f, "failed to decode response: {}", e),
+            Error::IoError(e) => write!(f, "I/O error: {}", e),
+            Error::Serial(e) => write!(f, "serial port error: {}", e),
+            Error::UnexpectedEndOfStream => write!(f, "connection closed"),
+            Error::Timeout {
+                request_id: Some(id),
+            } => write!(f, "timeout while waiting for response to request {}", id),
+            Error::Timeout { request_id: None } => write!(f, "timeout while waiting for response"),
+            Error::IncompatibleDevice {
+                device_id,
+                protocol_version,
+            } => write!(
+                f,
+                "incompatible device 0x{:04X} with protocol version {}",
+                device_id, protocol_version
+            ),
+            Error::Protocol(status) => write!(f, "device reported error status {:?}", status),
+            Error::MissingField(field) => write!(f, "response has no '{}' field", field),
+            Error::UnknownEnumValue { name, value } => {
+                write!(f, "unknown {} value {}", name, value)
+            }
+            Error::UnexpectedResponse { expected } => {
+                write!(f, "unexpected response, expected {}", expected)
+            }
+            Error::I2cLengthMismatch { expected, actual } => write!(
+                f,
+                "{} I2C operations requested, but {} results received",
+                expected, actual
+            ),
+            Error::BusMismatch { expected, actual } => write!(
+                f,
+                "response for I2C bus {} received, expected bus {}",
+                actual, expected
+            ),
+            Error::AddressMismatch { expected, actual } => write!(
+                f,
+                "response for I2C address 0x{:02X} received, expected 0x{:02X}",
+                actual, expected
+            ),
+            Error::I2cOperationMismatch { index } => write!(
+                f,
+                "I2C result {} does not match kind of requested operation",
+                index
+            ),
+            Error::ReadLengthMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "I2C read {} returned {} bytes, {} requested",
+                index, actual, expected
+            ),
+            Error::I2C(kind) => write!(f, "I2C error: {:?}", kind),
+            Error::InvalidBus { bus, available } => write!(
+                f,
+                "no I2C bus {}, available buses: {}",
+                bus,
+                available
+                    .iter()
+                    .map(|b| b.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::I2cPayloadTooLong { index, length, max } => write!(
+                f,
+                "I2C operation {} is {} bytes long, firmware accepts up to {}",
+                index, length, max
+            ),
+            Error::InvalidRegister { register } => write!(
+                f,
+                "register 0x{:04X} does not fit 8-bit register address",
+                register
+            ),
+            Error::MemoryOverflow { end, capacity } => write!(
+                f,
+                "memory transfer ends at 0x{:X}, beyond addressable 0x{:X} bytes",
+                end, capacity
+            ),
+            Error::InvalidMemoryLayout {
+                address_width,
+                page_size,
+            } => write!(
+                f,
+                "invalid memory layout: {}-byte offset, page size {:?}",
+                address_width, page_size
+            ),
+            // cause is reported by `source()`
+            Error::Context {
+                operation,
+                request_id,
+                ..
+            } => write!(f, "{} (request {})", operation, request_id),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::EncodeError(e) => Some(e),
+            Error::DecoderError(e) => Some(e),
+            Error::IoError(e) => Some(e),
+            Error::Serial(e) => Some(e),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -19,6 +230,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<tokio_serial::Error> for Error {
+    fn from(e: tokio_serial::Error) -> Self {
+        Error::Serial(e)
+    }
+}
+
 impl From<prost::EncodeError> for Error {
     fn from(e: prost::EncodeError) -> Self {
         Error::EncodeError(e)
@@ -33,10 +250,11 @@ impl From<prost::DecodeError> for Error {
 
 impl embedded_hal_async::i2c::Error for Error {
     fn kind(&self) -> ErrorKind {
-        if let Error::I2C(k) = self {
-            *k
-        } else {
-            ErrorKind::Other
+        match self.root() {
+            Error::I2C(k) => *k,
+            Error::InvalidBus { .. } => ErrorKind::Bus,
+            Error::I2cPayloadTooLong { .. } => ErrorKind::Overrun,
+            _ => ErrorKind::Other,
         }
     }
-}
\ No newline at end of file
+}
@@ -1,3 +1,5 @@
+use std::marker::PhantomData;
+
 use bytes::{Buf, BufMut, BytesMut};
 
 use prost::Message;
@@ -5,40 +7,176 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use super::messages::{Request, Response};
 
-pub(crate) struct ProtobufMDCodec;
+/// Default limit for the length of one frame body
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 4096;
 
-impl Decoder for ProtobufMDCodec {
-    type Item = Response;
-    type Error = super::Error;
+/// Longest possible varint encoding of u64
+const MAX_VARINT_LENGTH: usize = 10;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+/// `Info::Magick` + length delimited protobuf framing, decodes `D`, encodes the other direction
+///
+/// Client side decodes [`Response`] and encodes [`Request`] ([`ClientCodec`]), device side
+/// does the opposite ([`DeviceCodec`]). Undecodable frames are dropped and counted, decoder
+/// resynchronises on the next `Info::Magick`.
+pub struct ProtobufMDCodec<D = Response> {
+    max_frame_length: usize,
+
+    /// Frames dropped because they could not be decoded
+    corrupted_frames: u64,
+
+    _decode: PhantomData<D>,
+}
+
+impl<D> Default for ProtobufMDCodec<D> {
+    fn default() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+            corrupted_frames: 0,
+            _decode: PhantomData,
+        }
+    }
+}
+
+pub(crate) enum FrameHeader {
+    /// Need more bytes to parse length prefix
+    Incomplete,
+    /// Length prefix is not a valid varint
+    Invalid,
+    Valid {
+        header_len: usize,
+        body_len: u64,
+    },
+}
+
+/// Parse magick + varint length prefix at the start of `src`
+pub(crate) fn parse_header(src: &[u8]) -> FrameHeader {
+    let mut body_len = 0u64;
+    for (i, b) in src[1..].iter().take(MAX_VARINT_LENGTH).enumerate() {
+        body_len |= u64::from(b & 0x7F) << (7 * i);
+        if b & 0x80 == 0 {
+            return FrameHeader::Valid {
+                header_len: 1 + i + 1,
+                body_len,
+            };
+        }
+    }
+
+    if src.len() > MAX_VARINT_LENGTH {
+        FrameHeader::Invalid
+    } else {
+        FrameHeader::Incomplete
+    }
+}
+
+/// Codec for the host side: decodes [`Response`], encodes [`Request`]
+pub type ClientCodec = ProtobufMDCodec<Response>;
+
+/// Codec for the device side (simulators, bridges, sniffers): decodes [`Request`], encodes [`Response`]
+pub type DeviceCodec = ProtobufMDCodec<Request>;
+
+impl<D> ProtobufMDCodec<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> Self {
+        Self {
+            max_frame_length,
+            ..Self::default()
+        }
+    }
+
+    /// Number of frames dropped because they could not be decoded
+    pub fn corrupted_frames(&self) -> u64 {
+        self.corrupted_frames
+    }
+
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    /// Drop magick at the start of `src` so next decode resynchronises on the following one
+    fn drop_frame(&mut self, src: &mut BytesMut, reason: impl std::fmt::Display) {
+        self.corrupted_frames += 1;
+        log::warn!("Dropping corrupted frame: {}", reason);
+        src.advance(1);
+    }
+}
+
+impl<D: Message + Default> ProtobufMDCodec<D> {
+    /// Decode the next frame of `src` after the rest of a frame did not arrive in time
+    ///
+    /// Incomplete frame at the head is dropped: it may be a stray magick whose length
+    /// prefix swallows the frames behind it.
+    pub(crate) fn decode_stalled(&mut self, src: &mut BytesMut) -> Result<Option<D>, super::Error> {
         loop {
+            if let Some(msg) = self.decode(src)? {
+                return Ok(Some(msg));
+            }
             if src.is_empty() {
                 return Ok(None);
             }
-            
-            if src[0] == super::messages::Info::Magick as u8 {
-                break;
-            } else {
-                src.advance(1);
-            }
+            self.drop_frame(src, "frame not completed in time");
         }
+    }
+}
+
+impl<D: Message + Default> Decoder for ProtobufMDCodec<D> {
+    type Item = D;
+    type Error = super::Error;
 
-        let remaning = src.remaining();
-        let mut buf: BytesMut = src.clone();
-        buf.advance(1); // skip magick
-        
-        match super::messages::Response::decode_length_delimited(&mut buf) {
-            Ok(msg) => {
-                src.advance(remaning - buf.remaining());
-                Ok(Some(msg))
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match src
+                .iter()
+                .position(|b| *b == super::messages::Info::Magick as u8)
+            {
+                Some(pos) => src.advance(pos),
+                None => {
+                    src.clear();
+                    return Ok(None);
+                }
+            }
+
+            let (header_len, body_len) = match parse_header(src) {
+                FrameHeader::Incomplete => return Ok(None),
+                FrameHeader::Invalid => {
+                    self.drop_frame(src, "invalid length prefix");
+                    continue;
+                }
+                FrameHeader::Valid {
+                    header_len,
+                    body_len,
+                } => (header_len, body_len),
+            };
+
+            let max_frame_length = self.max_frame_length;
+            if body_len > max_frame_length as u64 {
+                self.drop_frame(
+                    src,
+                    format_args!("length {} exceeds limit {}", body_len, max_frame_length),
+                );
+                continue;
+            }
+
+            let frame_len = header_len + body_len as usize;
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
+                return Ok(None);
             }
-            Err(e) => {
-                if (e == prost::DecodeError::new("buffer underflow")) || buf.is_empty() {
-                    Ok(None)
-                } else {
-                    Err(e.into())
+
+            // Returning error here poisons the Framed stream, so drop this magick
+            // and resynchronise on the next one
+            match D::decode(&src[header_len..frame_len]) {
+                Ok(msg) => {
+                    src.advance(frame_len);
+                    return Ok(Some(msg));
                 }
+                Err(e) => self.drop_frame(src, e),
             }
         }
     }
@@ -54,14 +192,103 @@ impl Decoder for ProtobufMDCodec {
     }
 }
 
-impl Encoder<Request> for ProtobufMDCodec {
+/// Write magick and length delimited `msg` to `buf`
+fn encode_frame(msg: impl Message, buf: &mut BytesMut) -> Result<(), super::Error> {
+    buf.put_u8(super::messages::Info::Magick as u8);
+
+    msg.encode_length_delimited(buf)?;
+
+    Ok(())
+}
+
+impl Encoder<Request> for ProtobufMDCodec<Response> {
+    type Error = super::Error;
+
+    fn encode(&mut self, msg: Request, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_frame(msg, buf)
+    }
+}
+
+impl Encoder<Response> for ProtobufMDCodec<Request> {
     type Error = super::Error;
 
-    fn encode(&mut self, req_type: Request, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        buf.put_u8(super::messages::Info::Magick as u8);
+    fn encode(&mut self, msg: Response, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_frame(msg, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::messages::Info;
+
+    const MAGICK: u8 = Info::Magick as u8;
+
+    fn response(id: u32) -> Response {
+        Response {
+            id,
+            ..Default::default()
+        }
+    }
+
+    fn encoded(messages: &[Response]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for msg in messages {
+            DeviceCodec::new().encode(msg.clone(), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn frame_split_at_every_byte() {
+        let frame = encoded(&[response(300)]);
+
+        for split in 0..frame.len() {
+            let mut codec = ClientCodec::new();
+            let mut buf = BytesMut::from(&frame[..split]);
+            assert_eq!(codec.decode(&mut buf).unwrap(), None, "split at {}", split);
+            buf.extend_from_slice(&frame[split..]);
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(response(300)));
+            assert!(buf.is_empty());
+            assert_eq!(codec.corrupted_frames(), 0);
+        }
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let mut codec = ClientCodec::new();
+        let mut buf = encoded(&[response(1), response(2)]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(response(1)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(response(2)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn over_limit_length() {
+        let mut codec = ClientCodec::with_max_frame_length(64);
+        let mut buf = BytesMut::from(&[MAGICK, 0x7F][..]);
+        buf.extend_from_slice(&encoded(&[response(1)]));
+
+        // does not wait for 127 bytes, the frame behind the dropped magick is decoded
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(response(1)));
+        assert_eq!(codec.corrupted_frames(), 1);
+    }
 
-        req_type.encode_length_delimited(buf)?;
+    #[test]
+    fn invalid_varint() {
+        // nine continuation bytes may still end, the tenth can't
+        assert!(matches!(
+            parse_header(&[MAGICK, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+            FrameHeader::Incomplete
+        ));
+        let mut buf = BytesMut::from(&[MAGICK][..]);
+        buf.extend_from_slice(&[0xFF; 10]);
+        assert!(matches!(parse_header(&buf), FrameHeader::Invalid));
 
-        Ok(())
+        let mut codec = ClientCodec::new();
+        buf.extend_from_slice(&encoded(&[response(1)]));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(response(1)));
+        assert_eq!(codec.corrupted_frames(), 1);
     }
 }
@@ -0,0 +1,427 @@
+use std::future::Future;
+
+use embedded_hal_async::i2c::Operation;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{Error, I2c, I2cBusHandle, LaserSetup, SharedI2cBus};
+
+/// Byte order of multi-byte register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+/// Size of register address sent before data, 16-bit addresses are sent MSB first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegisterWidth {
+    #[default]
+    U8,
+    U16,
+}
+
+/// I2C device with register map: register address is written, then data is read or
+/// written from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterDevice {
+    pub address: u8,
+    pub register_width: RegisterWidth,
+    pub byte_order: ByteOrder,
+}
+
+impl RegisterDevice {
+    /// Device at `address` with 8-bit register addresses and little-endian values
+    pub fn new(address: u8) -> Self {
+        Self {
+            address,
+            register_width: RegisterWidth::U8,
+            byte_order: ByteOrder::LittleEndian,
+        }
+    }
+
+    pub fn register_width(mut self, width: RegisterWidth) -> Self {
+        self.register_width = width;
+        self
+    }
+
+    pub fn byte_order(mut self, order: ByteOrder) -> Self {
+        self.byte_order = order;
+        self
+    }
+
+    fn register_bytes(&self, register: u16) -> Result<Vec<u8>, Error> {
+        match self.register_width {
+            RegisterWidth::U8 => u8::try_from(register)
+                .map(|r| vec![r])
+                .map_err(|_| Error::InvalidRegister { register }),
+            RegisterWidth::U16 => Ok(register.to_be_bytes().to_vec()),
+        }
+    }
+}
+
+/// Register value convertible from/to bytes in both orders
+trait RegisterValue: Copy {
+    const SIZE: usize;
+
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Self;
+    fn to_bytes(self, order: ByteOrder) -> Vec<u8>;
+}
+
+macro_rules! register_value {
+    ($($t:ty),*) => {$(
+        impl RegisterValue for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn from_bytes(bytes: &[u8], order: ByteOrder) -> Self {
+                let bytes = bytes.try_into().expect("buffer of register size");
+                match order {
+                    ByteOrder::LittleEndian => <$t>::from_le_bytes(bytes),
+                    ByteOrder::BigEndian => <$t>::from_be_bytes(bytes),
+                }
+            }
+
+            fn to_bytes(self, order: ByteOrder) -> Vec<u8> {
+                match order {
+                    ByteOrder::LittleEndian => self.to_le_bytes().to_vec(),
+                    ByteOrder::BigEndian => self.to_be_bytes().to_vec(),
+                }
+            }
+        }
+    )*};
+}
+
+register_value!(u8, u16, u32, f32);
+
+mod sealed {
+    use super::*;
+
+    /// I2C implementation of this crate, its transactions can be sent between threads
+    pub trait SendI2c: Send + Sized {
+        fn send_transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> impl Future<Output = Result<(), Error>> + Send;
+    }
+
+    impl<IO: AsyncRead + AsyncWrite + Unpin + Send> SendI2c for LaserSetup<IO> {
+        fn send_transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> impl Future<Output = Result<(), Error>> + Send {
+            self.transaction(address, operations)
+        }
+    }
+
+    impl<IO: AsyncRead + AsyncWrite + Unpin + Send> SendI2c for SharedI2cBus<'_, IO> {
+        fn send_transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> impl Future<Output = Result<(), Error>> + Send {
+            self.transaction(address, operations)
+        }
+    }
+
+    impl SendI2c for I2cBusHandle {
+        fn send_transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> impl Future<Output = Result<(), Error>> + Send {
+            self.transaction(address, operations)
+        }
+    }
+}
+
+async fn read_value<V: RegisterValue>(
+    i2c: &mut impl I2cRegisters,
+    device: &RegisterDevice,
+    register: u16,
+) -> Result<V, Error> {
+    let mut buf = vec![0u8; V::SIZE];
+    i2c.read_reg(device, register, &mut buf).await?;
+    Ok(V::from_bytes(&buf, device.byte_order))
+}
+
+async fn write_value<V: RegisterValue>(
+    i2c: &mut impl I2cRegisters,
+    device: &RegisterDevice,
+    register: u16,
+    value: V,
+) -> Result<(), Error> {
+    i2c.write_reg(device, register, &value.to_bytes(device.byte_order))
+        .await
+}
+
+/// Typed register access for any of this crate's I2C implementations:
+/// [`LaserSetup`], [`SharedI2cBus`], [`I2cBusHandle`]
+///
+/// Every read or write is a single I2C sequence. `update_reg_*` are a read and a write,
+/// see [`I2cRegisters::update_reg_u8`].
+///
+/// ```no_run
+/// # async fn test(mut laser: laser_setup_interface::LaserSetup) -> Result<(), laser_setup_interface::Error> {
+/// use laser_setup_interface::{I2cRegisters, RegisterDevice};
+///
+/// let freq_meter = RegisterDevice::new(0x0B);
+/// let frequency = laser.read_reg_f32(&freq_meter, 0x08).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait I2cRegisters: sealed::SendI2c {
+    /// Read `buf.len()` bytes starting from `register`
+    fn read_reg(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async move {
+            let register = device.register_bytes(register)?;
+            self.send_transaction(
+                device.address,
+                &mut [Operation::Write(&register), Operation::Read(buf)],
+            )
+            .await
+        }
+    }
+
+    /// Write `data` starting from `register`
+    fn write_reg(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async move {
+            let mut buf = device.register_bytes(register)?;
+            buf.extend_from_slice(data);
+            self.send_transaction(device.address, &mut [Operation::Write(&buf)])
+                .await
+        }
+    }
+
+    fn read_reg_u8(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+    ) -> impl Future<Output = Result<u8, Error>> + Send {
+        read_value(self, device, register)
+    }
+
+    fn read_reg_u16(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+    ) -> impl Future<Output = Result<u16, Error>> + Send {
+        read_value(self, device, register)
+    }
+
+    fn read_reg_u32(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+    ) -> impl Future<Output = Result<u32, Error>> + Send {
+        read_value(self, device, register)
+    }
+
+    fn read_reg_f32(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+    ) -> impl Future<Output = Result<f32, Error>> + Send {
+        read_value(self, device, register)
+    }
+
+    fn write_reg_u8(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        value: u8,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        write_value(self, device, register, value)
+    }
+
+    fn write_reg_u16(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        value: u16,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        write_value(self, device, register, value)
+    }
+
+    fn write_reg_u32(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        value: u32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        write_value(self, device, register, value)
+    }
+
+    fn write_reg_f32(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        value: f32,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        write_value(self, device, register, value)
+    }
+
+    /// Replace bits of `mask` with `bits`, other bits are kept; returns the written value
+    ///
+    /// Not atomic: read and write are separate sequences, firmware has no read-modify-write.
+    /// Exclusively borrowed [`LaserSetup`] can't be used in between, but other users of
+    /// a [`SharedI2cBus`] mutex or [`LaserSetupHandle`](crate::LaserSetupHandle) clones may
+    /// access the register between them.
+    fn update_reg_u8(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        mask: u8,
+        bits: u8,
+    ) -> impl Future<Output = Result<u8, Error>> + Send {
+        async move {
+            let value = (self.read_reg_u8(device, register).await? & !mask) | (bits & mask);
+            self.write_reg_u8(device, register, value).await?;
+            Ok(value)
+        }
+    }
+
+    /// See [`I2cRegisters::update_reg_u8`]
+    fn update_reg_u16(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        mask: u16,
+        bits: u16,
+    ) -> impl Future<Output = Result<u16, Error>> + Send {
+        async move {
+            let value = (self.read_reg_u16(device, register).await? & !mask) | (bits & mask);
+            self.write_reg_u16(device, register, value).await?;
+            Ok(value)
+        }
+    }
+
+    /// See [`I2cRegisters::update_reg_u8`]
+    fn update_reg_u32(
+        &mut self,
+        device: &RegisterDevice,
+        register: u16,
+        mask: u32,
+        bits: u32,
+    ) -> impl Future<Output = Result<u32, Error>> + Send {
+        async move {
+            let value = (self.read_reg_u32(device, register).await? & !mask) | (bits & mask);
+            self.write_reg_u32(device, register, value).await?;
+            Ok(value)
+        }
+    }
+}
+
+impl<T: sealed::SendI2c> I2cRegisters for T {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mock::{FreqMeter, I2cOp, MockDevice, RegisterFile, RequestKind};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+    const ADDRESS: u8 = 0x40;
+
+    /// Register file at [`ADDRESS`] with every register holding its own address
+    fn device() -> MockDevice {
+        let device = MockDevice::new();
+        device.add_i2c_bus(0, 100_000);
+        device.attach_i2c_device(0, ADDRESS, RegisterFile::from((0..16).collect::<Vec<u8>>()));
+        device.attach_i2c_device(0, FreqMeter::ADDRESS, FreqMeter::new(32768.0));
+        device
+    }
+
+    fn registers(device: &MockDevice) -> Vec<u8> {
+        device
+            .with_i2c_device(0, ADDRESS, |r: &mut RegisterFile| r.registers().to_vec())
+            .unwrap()
+    }
+
+    fn last_operations(device: &MockDevice) -> Vec<I2cOp> {
+        match device.received().pop().unwrap().kind {
+            RequestKind::I2cSequence { operations, .. } => operations,
+            kind => panic!("not an I2C sequence: {:?}", kind),
+        }
+    }
+
+    #[tokio::test]
+    async fn byte_order() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+        let le = RegisterDevice::new(ADDRESS);
+        let be = le.byte_order(ByteOrder::BigEndian);
+
+        assert_eq!(laser.read_reg_u16(&le, 2).await.unwrap(), 0x0302);
+        assert_eq!(laser.read_reg_u16(&be, 2).await.unwrap(), 0x0203);
+        assert_eq!(laser.read_reg_u32(&be, 4).await.unwrap(), 0x04050607);
+
+        laser.write_reg_u16(&be, 8, 0xABCD).await.unwrap();
+        laser.write_reg_u32(&le, 10, 0x11223344).await.unwrap();
+        assert_eq!(
+            registers(&device)[8..14],
+            [0xAB, 0xCD, 0x44, 0x33, 0x22, 0x11]
+        );
+
+        let meter = RegisterDevice::new(FreqMeter::ADDRESS);
+        let frequency = laser
+            .read_reg_f32(&meter, FreqMeter::FREQUENCY_REG as u16)
+            .await
+            .unwrap();
+        assert_eq!(frequency, 32768.0);
+    }
+
+    #[tokio::test]
+    async fn register_width() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+        let wide = RegisterDevice::new(ADDRESS).register_width(RegisterWidth::U16);
+
+        // 16-bit register address is sent MSB first
+        laser.read_reg_u8(&wide, 0x0102).await.unwrap();
+        assert_eq!(
+            last_operations(&device),
+            [I2cOp::Write(vec![0x01, 0x02]), I2cOp::Read(1)]
+        );
+
+        let sent = device.received().len();
+        let err = laser
+            .write_reg_u8(&RegisterDevice::new(ADDRESS), 0x100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidRegister { register: 0x100 }));
+        assert_eq!(device.received().len(), sent);
+    }
+
+    #[tokio::test]
+    async fn update_masked_bits() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+        let dev = RegisterDevice::new(ADDRESS);
+
+        laser.write_reg_u8(&dev, 3, 0b1010_1010).await.unwrap();
+        let value = laser.update_reg_u8(&dev, 3, 0x0F, 0xF5).await.unwrap();
+        assert_eq!(value, 0b1010_0101);
+        assert_eq!(registers(&device)[3], 0b1010_0101);
+
+        // registers 4 and 5 hold 0x0504
+        let value = laser.update_reg_u16(&dev, 4, 0xFF00, 0x1234).await.unwrap();
+        assert_eq!(value, 0x1204);
+        assert_eq!(registers(&device)[4..6], [0x04, 0x12]);
+
+        let value = laser.update_reg_u32(&dev, 8, 0x0000_FFFF, 0).await.unwrap();
+        assert_eq!(value, 0x0B0A_0000);
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use embedded_hal_async::i2c::{ErrorKind, Operation};
+use futures::future::join_all;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{Error, LaserSetup, LaserSetupHandle};
+
+/// How to find out whether a device answers at an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProbeMethod {
+    /// Like `i2cdetect`: one-byte read at 0x30..=0x37 and 0x50..=0x5F, zero-length write
+    /// elsewhere
+    #[default]
+    Auto,
+    /// Zero-length write, some write-only devices do not answer reads, but it may be taken
+    /// as a command (or corrupt an EEPROM) by some chips
+    QuickWrite,
+    /// One-byte read, may lock up write-only devices or pop data from a FIFO
+    ReadByte,
+}
+
+impl ProbeMethod {
+    fn read_byte(self, address: u8) -> bool {
+        match self {
+            ProbeMethod::Auto => matches!(address, 0x30..=0x37 | 0x50..=0x5F),
+            ProbeMethod::QuickWrite => false,
+            ProbeMethod::ReadByte => true,
+        }
+    }
+}
+
+/// Settings of [`LaserSetup::scan_bus`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub method: ProbeMethod,
+    pub addresses: RangeInclusive<u8>,
+    /// Do not probe reserved 0x00..=0x07 and 0x78..=0x7F (general call, CBUS, HS-mode,
+    /// 10-bit addressing)
+    pub skip_reserved: bool,
+    /// Stop at the first error other than NAK, otherwise it is recorded and scanning goes on
+    pub stop_on_error: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            method: ProbeMethod::Auto,
+            addresses: 0x00..=0x7F,
+            skip_reserved: true,
+            stop_on_error: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    fn probed(&self, address: u8) -> bool {
+        self.addresses.contains(&address)
+            && (!self.skip_reserved || (0x08..=0x77).contains(&address))
+    }
+}
+
+/// Result of probing one address
+#[derive(Debug)]
+pub enum AddressStatus {
+    /// Excluded by [`ScanOptions`]
+    NotProbed,
+    /// Address not acknowledged
+    Absent,
+    Present,
+    Failed(Error),
+}
+
+/// Result of [`LaserSetup::scan_bus`], displayed as `i2cdetect` grid
+///
+/// Found devices are shown by address, `--` is no answer, `XX` is a failed probe.
+#[derive(Debug)]
+pub struct BusScan {
+    pub bus: u32,
+    /// Indexed by address, 0x00..=0x7F
+    pub addresses: Vec<AddressStatus>,
+}
+
+impl BusScan {
+    /// Addresses of found devices
+    pub fn found(&self) -> impl Iterator<Item = u8> + '_ {
+        self.addresses
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| matches!(s, AddressStatus::Present))
+            .map(|(a, _)| a as u8)
+    }
+
+    /// Probes failed with an error other than NAK
+    pub fn errors(&self) -> impl Iterator<Item = (u8, &Error)> + '_ {
+        self.addresses
+            .iter()
+            .enumerate()
+            .filter_map(|(a, s)| match s {
+                AddressStatus::Failed(e) => Some((a as u8, e)),
+                _ => None,
+            })
+    }
+}
+
+impl fmt::Display for BusScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "   ")?;
+        for col in 0..16 {
+            write!(f, "  {:x}", col)?;
+        }
+        writeln!(f)?;
+
+        for (row, statuses) in self.addresses.chunks(16).enumerate() {
+            write!(f, "{:02x}:", row * 16)?;
+            for (col, status) in statuses.iter().enumerate() {
+                match status {
+                    AddressStatus::NotProbed => write!(f, "   ")?,
+                    AddressStatus::Absent => write!(f, " --")?,
+                    AddressStatus::Present => write!(f, " {:02x}", row * 16 + col)?,
+                    AddressStatus::Failed(_) => write!(f, " XX")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connection that can run I2C transactions on any bus
+trait BusAccess {
+    async fn transaction_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error>;
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> BusAccess for &mut LaserSetup<IO> {
+    async fn transaction_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        LaserSetup::transaction_on(self, bus, address, operations).await
+    }
+}
+
+impl BusAccess for &LaserSetupHandle {
+    async fn transaction_on(
+        &mut self,
+        bus: u32,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        LaserSetupHandle::transaction_on(self, bus, address, operations).await
+    }
+}
+
+/// Errors after which other addresses can't be probed either
+fn is_fatal(e: &Error) -> bool {
+    matches!(
+        e.root(),
+        Error::IoError(_)
+            | Error::Serial(_)
+            | Error::UnexpectedEndOfStream
+            | Error::InvalidBus { .. }
+            | Error::EncodeError(_)
+    )
+}
+
+async fn scan(mut io: impl BusAccess, bus: u32, options: &ScanOptions) -> Result<BusScan, Error> {
+    let mut addresses = Vec::with_capacity(0x80);
+
+    for address in 0..=0x7F {
+        if !options.probed(address) {
+            addresses.push(AddressStatus::NotProbed);
+            continue;
+        }
+
+        let mut buf = [0u8; 1];
+        let mut ops = if options.method.read_byte(address) {
+            [Operation::Read(&mut buf)]
+        } else {
+            [Operation::Write(&[])]
+        };
+
+        let status = match io.transaction_on(bus, address, &mut ops).await {
+            Ok(()) => AddressStatus::Present,
+            Err(e) if matches!(e.root(), Error::I2C(ErrorKind::NoAcknowledge(_))) => {
+                AddressStatus::Absent
+            }
+            Err(e) if options.stop_on_error || is_fatal(&e) => return Err(e),
+            Err(e) => {
+                log::warn!(
+                    "Probing 0x{:02x} on I2C bus {} failed: {}",
+                    address,
+                    bus,
+                    e.root()
+                );
+                AddressStatus::Failed(e)
+            }
+        };
+        addresses.push(status);
+    }
+
+    Ok(BusScan { bus, addresses })
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> LaserSetup<IO> {
+    /// Probe addresses of `bus` one by one, selected bus is not changed
+    pub async fn scan_bus(&mut self, bus: u32, options: &ScanOptions) -> Result<BusScan, Error> {
+        scan(self, bus, options).await
+    }
+
+    /// [`LaserSetup::scan_bus`] on every bus of [`LaserSetup::enumerate_i2c_buses`] in turn
+    ///
+    /// Use [`LaserSetupHandle::scan_all_buses`] to pipeline probes of different buses.
+    pub async fn scan_all_buses(&mut self, options: &ScanOptions) -> Result<Vec<BusScan>, Error> {
+        let mut res = vec![];
+        for bus in self.enumerate_i2c_buses().await? {
+            res.push(self.scan_bus(bus.id, options).await?);
+        }
+        Ok(res)
+    }
+}
+
+impl LaserSetupHandle {
+    /// See [`LaserSetup::scan_bus`]
+    pub async fn scan_bus(&self, bus: u32, options: &ScanOptions) -> Result<BusScan, Error> {
+        scan(self, bus, options).await
+    }
+
+    /// Scan every bus of [`LaserSetupHandle::enumerate_i2c_buses`] concurrently, requests
+    /// of different buses are interleaved
+    ///
+    /// With the default [`LaserSetup::set_pipeline_window`] of 1 the device still gets one
+    /// request at a time, so this is no faster than [`LaserSetup::scan_all_buses`]. Set a
+    /// wider window before [`LaserSetup::spawn`] to have probes of several buses in flight.
+    pub async fn scan_all_buses(&self, options: &ScanOptions) -> Result<Vec<BusScan>, Error> {
+        let buses = self.enumerate_i2c_buses().await?;
+        join_all(buses.iter().map(|bus| self.scan_bus(bus.id, options)))
+            .await
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::mock::{Eeprom24, FreqMeter, I2cFault, I2cOp, MockDevice, RequestKind};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn device() -> MockDevice {
+        let device = MockDevice::new();
+        device.add_i2c_bus(0, 100_000);
+        device.attach_i2c_device(0, FreqMeter::ADDRESS, FreqMeter::new(1.0));
+        device.attach_i2c_device(0, 0x50, Eeprom24::c02());
+        device
+    }
+
+    fn options(addresses: RangeInclusive<u8>) -> ScanOptions {
+        ScanOptions {
+            addresses,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_probe() {
+        let device = device();
+        let mut laser = device.connect(TIMEOUT);
+
+        let scan = laser.scan_bus(0, &ScanOptions::default()).await.unwrap();
+
+        assert_eq!(scan.found().collect::<Vec<_>>(), [0x0B, 0x50]);
+        assert!(matches!(scan.addresses[0x07], AddressStatus::NotProbed));
+        assert!(matches!(scan.addresses[0x78], AddressStatus::NotProbed));
+        assert!(matches!(scan.addresses[0x0C], AddressStatus::Absent));
+
+        let mut probed = 0;
+        for request in device.received() {
+            if let RequestKind::I2cSequence {
+                address,
+                operations,
+                ..
+            } = request.kind
+            {
+                let address = address as u8;
+                assert!((0x08..=0x77).contains(&address));
+                let expected = match address {
+                    0x30..=0x37 | 0x50..=0x5F => I2cOp::Read(1),
+                    _ => I2cOp::Write(vec![]),
+                };
+                assert_eq!(operations, [expected], "address 0x{:02x}", address);
+                probed += 1;
+            }
+        }
+        assert_eq!(probed, 0x70);
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let device = device();
+        device.set_i2c_fault(0, Some(0x20), Some(I2cFault::Timeout));
+        let mut laser = device.connect(TIMEOUT);
+
+        let scan = laser.scan_bus(0, &options(0x1F..=0x21)).await.unwrap();
+        assert!(matches!(scan.addresses[0x1F], AddressStatus::Absent));
+        assert!(matches!(scan.addresses[0x21], AddressStatus::Absent));
+        let errors: Vec<_> = scan.errors().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 0x20);
+        assert!(matches!(errors[0].1.root(), Error::Timeout { .. }));
+
+        let stop = ScanOptions {
+            stop_on_error: true,
+            ..options(0x1F..=0x21)
+        };
+        let err = laser.scan_bus(0, &stop).await.unwrap_err();
+        assert!(matches!(err.root(), Error::Timeout { .. }));
+    }
+
+    #[tokio::test]
+    async fn grid() {
+        let device = device();
+        device.set_i2c_fault(0, Some(0x49), Some(I2cFault::Timeout));
+        let mut laser = device.connect(TIMEOUT);
+
+        let scan = laser.scan_bus(0, &options(0x48..=0x52)).await.unwrap();
+        let grid = scan.to_string();
+        let lines: Vec<_> = grid.lines().collect();
+
+        assert_eq!(lines.len(), 9);
+        assert_eq!(
+            lines[0],
+            "     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f"
+        );
+        assert_eq!(lines[1], format!("00:{}", "   ".repeat(16)));
+        assert_eq!(
+            lines[5],
+            format!("40:{} -- XX{}", "   ".repeat(8), " --".repeat(6))
+        );
+        assert_eq!(lines[6], format!("50: 50 -- --{}", "   ".repeat(13)));
+    }
+}